@@ -1,12 +1,21 @@
 use serde::Serialize;
-use crate::miner::Handle as MinerHandle;
+use crate::miner::{Handle as MinerHandle, RunLimits};
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::generator::Handle as GeneratorHandle;
 use crate::network::message::Message;
+use crate::transaction::{Mempool, SignedTransaction, StateChain};
+use crate::block::{Block, Header as BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{Hashable, H256};
+use crate::network::orphan::OrphanPool;
 
 use log::info;
 use std::collections::HashMap;
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use tiny_http::Header;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
@@ -17,14 +26,171 @@ pub struct Server {
     miner: MinerHandle,
     generator: GeneratorHandle,
     network: NetworkServerHandle,
+    mempool: Arc<Mutex<Mempool>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    statechain: Arc<Mutex<StateChain>>,
+    orphans: Arc<Mutex<OrphanPool>>,
 }
 
+/// Most headers returned by one `/blockchain/headers` request
+const MAX_HEADERS: usize = 2000;
+
 #[derive(Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+#[derive(Serialize)]
+struct BannedPeer {
+    ip: String,
+    /// Seconds until the ban ends
+    remaining: u64,
+}
+
+#[derive(Serialize)]
+struct PeerInfo {
+    id: usize,
+    addr: String,
+    direction: String,
+    ready: bool,
+    best_height: Option<u32>,
+    /// Seconds since the connection was established
+    connected: u64,
+    /// Seconds since the peer last sent a message
+    last_seen: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    misbehavior: u32,
+}
+
+#[derive(Serialize)]
+struct HashRate {
+    /// Hashes per second over the last second
+    hash_rate: u64,
+}
+
+#[derive(Serialize)]
+struct BlockTemplate {
+    height: u32,
+    parent: String,
+    difficulty: String,
+    timestamp: u64,
+    merkle_root: String,
+    /// The serialized coinbase transaction in hex
+    coinbase: String,
+    /// The other serialized transactions in hex, in block order
+    transactions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ChainTip {
+    hash: String,
+    height: u32,
+    chain_work: String,
+}
+
+#[derive(Serialize)]
+struct HeaderInfo {
+    hash: String,
+    height: u32,
+    parent: String,
+    nonce: u32,
+    difficulty: String,
+    timestamp: u64,
+    merkle_root: String,
+}
+
+#[derive(Serialize)]
+struct BlockInfo {
+    #[serde(flatten)]
+    header: HeaderInfo,
+    /// 0 for blocks off the longest chain
+    confirmations: u32,
+    /// Hashes of the transactions, the coinbase first
+    transactions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct OutputInfo {
+    address: String,
+    value: u64,
+}
+
+#[derive(Serialize)]
+struct TransactionInfo {
+    hash: String,
+    /// The block of the longest chain holding the transaction
+    block: Option<String>,
+    height: Option<u32>,
+    confirmations: u32,
+    in_mempool: bool,
+    /// The spent outputs as `tx_hash:index`, empty for a coinbase
+    inputs: Vec<String>,
+    outputs: Vec<OutputInfo>,
+}
+
+#[derive(Serialize)]
+struct ForkInfo {
+    /// Blocks received before their parent
+    orphans: usize,
+    /// Blocks off the longest chain
+    stale_blocks: usize,
+    /// The last blocks of the branches off the longest chain
+    fork_tips: Vec<String>,
+}
+
+fn header_info(header: &BlockHeader, height: u32) -> HeaderInfo {
+    HeaderInfo {
+        hash: header.hash().to_string(),
+        height,
+        parent: header.parent.to_string(),
+        nonce: header.nonce,
+        difficulty: header.difficulty.to_string(),
+        timestamp: header.timestamp as u64,
+        merkle_root: header.merkle_root.to_string(),
+    }
+}
+
+fn transaction_info(signed_tx: &SignedTransaction, block: Option<(H256, u32, u32)>, in_mempool: bool) -> TransactionInfo {
+    let tx = &signed_tx.transaction;
+    let inputs = if tx.is_coinbase() {
+        vec![]
+    } else {
+        tx.in_put.iter().map(|input| format!("{}:{}", input.tx_hash, input.index)).collect()
+    };
+    TransactionInfo {
+        hash: signed_tx.hash().to_string(),
+        block: block.map(|(hash, _, _)| hash.to_string()),
+        height: block.map(|(_, height, _)| height),
+        confirmations: block.map_or(0, |(_, _, confirmations)| confirmations),
+        in_mempool,
+        inputs,
+        outputs: tx.out_put.iter()
+            .map(|output| OutputInfo { address: output.address.to_string(), value: output.value })
+            .collect(),
+    }
+}
+
+/// Read the block hash under `key`
+fn hash_param(params: &HashMap<String, String>, key: &str) -> Result<Option<H256>, String> {
+    match params.get(key) {
+        Some(v) => v.parse::<H256>().map(Some).map_err(|e| format!("error parsing {}: {}", key, e)),
+        None => Ok(None),
+    }
+}
+
+/// Read the number under `key`
+fn number_param<T: std::str::FromStr>(params: &HashMap<String, String>, key: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match params.get(key) {
+        Some(v) => v.parse::<T>().map(Some).map_err(|e| format!("error parsing {}: {}", key, e)),
+        None => Ok(None),
+    }
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -38,12 +204,44 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+/// Read the optional run limits of a start request: `duration` in seconds, and the number of
+/// items under `count_key`
+fn run_limits(params: &HashMap<String, String>, count_key: &str) -> Result<RunLimits, String> {
+    let duration = match params.get("duration") {
+        Some(v) => Some(Duration::from_secs(
+            v.parse::<u64>().map_err(|e| format!("error parsing duration: {}", e))?,
+        )),
+        None => None,
+    };
+    let count = match params.get(count_key) {
+        Some(v) => Some(
+            v.parse::<u64>().map_err(|e| format!("error parsing {}: {}", count_key, e))?,
+        ),
+        None => None,
+    };
+    Ok(RunLimits { duration, count })
+}
+
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         generator: &GeneratorHandle,
         network: &NetworkServerHandle,
+        mempool: &Arc<Mutex<Mempool>>,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        statechain: &Arc<Mutex<StateChain>>,
+        orphans: &Arc<Mutex<OrphanPool>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -51,12 +249,20 @@ impl Server {
             miner: miner.clone(),
             generator: generator.clone(),
             network: network.clone(),
+            mempool: Arc::clone(mempool),
+            blockchain: Arc::clone(blockchain),
+            statechain: Arc::clone(statechain),
+            orphans: Arc::clone(orphans),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let generator = server.generator.clone();
                 let network = server.network.clone();
+                let mempool = Arc::clone(&server.mempool);
+                let blockchain = Arc::clone(&server.blockchain);
+                let statechain = Arc::clone(&server.statechain);
+                let orphans = Arc::clone(&server.orphans);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -89,9 +295,84 @@ impl Server {
                                     return;
                                 }
                             };
-                            miner.start(lambda);
+                            let limits = match run_limits(&params, "blocks") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            if miner.start_limited(lambda, limits) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "miner stopped");
+                            }
+                        }
+                        "/miner/pause" => {
+                            if miner.pause() {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "miner stopped");
+                            }
+                        }
+                        "/miner/stop" => {
+                            miner.stop();
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/hashrate" => {
+                            respond_json!(req, HashRate { hash_rate: miner.hash_rate() });
+                        }
+                        "/miner/getblocktemplate" => {
+                            let template = miner.assembler().template();
+                            let mut transactions = template.transactions.iter()
+                                .map(|tx| hex::encode(bincode::serialize(tx).unwrap()));
+                            let header = &template.header;
+                            respond_json!(req, BlockTemplate {
+                                height: template.height,
+                                parent: header.parent.to_string(),
+                                difficulty: header.difficulty.to_string(),
+                                timestamp: header.timestamp as u64,
+                                merkle_root: header.merkle_root.to_string(),
+                                coinbase: transactions.next().unwrap(),
+                                transactions: transactions.collect(),
+                            });
+                        }
+                        "/miner/submitblock" => {
+                            // the serialized block in hex, as the request body or `block` parameter
+                            let mut req = req;
+                            // hex doubles the size of the largest block, with some room for whitespace
+                            let max_body = 2 * blockchain.lock().unwrap().params.max_block_size as u64 + 16;
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().take(max_body + 1).read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading block: {}", e));
+                                return;
+                            }
+                            if body.len() as u64 > max_body {
+                                respond_result!(req, false, "block too large");
+                                return;
+                            }
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let encoded = match params.get("block") {
+                                Some(v) if body.trim().is_empty() => v.clone(),
+                                _ => body.trim().to_string(),
+                            };
+                            let block = match hex::decode(&encoded) {
+                                Ok(bytes) => bincode::deserialize::<Block>(&bytes)
+                                    .map_err(|e| format!("error decoding block: {}", e)),
+                                Err(e) => Err(format!("error decoding block: {}", e)),
+                            };
+                            let block = match block {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match miner.assembler().submit(&block) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, format!("block rejected: {:?}", e)),
+                            }
+                        }
                         "/generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -113,13 +394,223 @@ impl Server {
                                     return;
                                 }
                             };
-                            generator.start(lambda);
+                            let limits = match run_limits(&params, "transactions") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            if generator.start_limited(lambda, limits) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "generator stopped");
+                            }
+                        }
+                        "/generator/pause" => {
+                            if generator.pause() {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "generator stopped");
+                            }
+                        }
+                        "/generator/stop" => {
+                            generator.stop();
                             respond_result!(req, true, "ok");
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            let now = Instant::now();
+                            let peers: Vec<PeerInfo> = network
+                                .peers()
+                                .into_iter()
+                                .map(|peer| PeerInfo {
+                                    id: peer.id,
+                                    addr: peer.addr.to_string(),
+                                    direction: format!("{:?}", peer.direction),
+                                    ready: peer.ready,
+                                    best_height: peer.best_height,
+                                    connected: now.duration_since(peer.connected_at).as_secs(),
+                                    last_seen: now.duration_since(peer.last_seen).as_secs(),
+                                    bytes_received: peer.bytes_received,
+                                    bytes_sent: peer.bytes_sent,
+                                    misbehavior: peer.misbehavior,
+                                })
+                                .collect();
+                            respond_json!(req, peers);
+                        }
+                        "/network/disconnect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let id = match params.get("id") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing id");
+                                    return;
+                                }
+                            };
+                            let id = match id.parse::<usize>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing id: {}", e));
+                                    return;
+                                }
+                            };
+                            if network.disconnect(id) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "no such peer");
+                            }
+                        }
+                        "/network/banned" => {
+                            let banned: Vec<BannedPeer> = network
+                                .addrman()
+                                .lock()
+                                .unwrap()
+                                .banned(Instant::now())
+                                .into_iter()
+                                .map(|(ip, remaining)| BannedPeer {
+                                    ip: ip.to_string(),
+                                    remaining: remaining.as_secs(),
+                                })
+                                .collect();
+                            respond_json!(req, banned);
+                        }
+                        "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing ip");
+                                    return;
+                                }
+                            };
+                            let ip = match ip.parse::<IpAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing ip: {}", e));
+                                    return;
+                                }
+                            };
+                            if network.addrman().lock().unwrap().unban(&ip) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "not banned");
+                            }
+                        }
+                        "/mempool/stats" => {
+                            let stats = mempool.lock().unwrap().stats();
+                            respond_json!(req, stats);
+                        }
+                        "/blockchain/tip" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let tip = blockchain.tip();
+                            respond_json!(req, ChainTip {
+                                hash: tip.to_string(),
+                                height: blockchain.tip_height,
+                                chain_work: blockchain.chain_work(&tip).unwrap().to_string(),
+                            });
+                        }
+                        "/blockchain/block" => {
+                            // by `hash`, or by `height` in the longest chain
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let blockchain = blockchain.lock().unwrap();
+                            let hash = match (hash_param(&params, "hash"), number_param::<u32>(&params, "height")) {
+                                (Ok(Some(hash)), _) => Some(hash),
+                                (Ok(None), Ok(Some(height))) => blockchain.hash_at_height(height),
+                                (Ok(None), Ok(None)) => {
+                                    respond_result!(req, false, "missing hash or height");
+                                    return;
+                                }
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let block = match hash.and_then(|hash| blockchain.data.get(&hash)) {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "block not found");
+                                    return;
+                                }
+                            };
+                            let content = &block.block_content;
+                            respond_json!(req, BlockInfo {
+                                header: header_info(&content.header, block.block_height),
+                                confirmations: blockchain.confirmations(&content.hash()).unwrap(),
+                                transactions: content.content.content.iter().map(|tx| tx.hash().to_string()).collect(),
+                            });
+                        }
+                        "/blockchain/headers" => {
+                            // the headers of the longest chain from height `start`, at most `count`
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let (start, count) = match (number_param::<usize>(&params, "start"), number_param::<usize>(&params, "count")) {
+                                (Ok(start), Ok(count)) => (start.unwrap_or(0), count.unwrap_or(MAX_HEADERS).min(MAX_HEADERS)),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let headers: Vec<HeaderInfo> = blockchain
+                                .all_blocks_in_longest_chain()
+                                .iter()
+                                .enumerate()
+                                .skip(start)
+                                .take(count)
+                                .map(|(height, hash)| header_info(&blockchain.data[hash].block_content.header, height as u32))
+                                .collect();
+                            respond_json!(req, headers);
+                        }
+                        "/blockchain/tx" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let hash = match hash_param(&params, "hash") {
+                                Ok(Some(v)) => v,
+                                Ok(None) => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let statechain = statechain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            let in_mempool = mempool.data.get(&hash).map(|entry| &entry.transaction);
+                            let info = match statechain.transaction_block(&hash) {
+                                Some(block_hash) => {
+                                    let block = &blockchain.data[&block_hash];
+                                    let signed_tx = block.block_content.content.content.iter()
+                                        .find(|tx| tx.hash() == hash)
+                                        .unwrap();
+                                    let confirmations = blockchain.confirmations(&block_hash).unwrap();
+                                    transaction_info(signed_tx, Some((block_hash, block.block_height, confirmations)), in_mempool.is_some())
+                                }
+                                None => match in_mempool {
+                                    Some(signed_tx) => transaction_info(signed_tx, None, true),
+                                    None => {
+                                        respond_result!(req, false, "transaction not found");
+                                        return;
+                                    }
+                                },
+                            };
+                            respond_json!(req, info);
+                        }
+                        "/blockchain/forks" => {
+                            let orphans = orphans.lock().unwrap().len();
+                            let blockchain = blockchain.lock().unwrap();
+                            respond_json!(req, ForkInfo {
+                                orphans,
+                                stale_blocks: blockchain.data.len() - blockchain.all_blocks_in_longest_chain().len(),
+                                fork_tips: blockchain.fork_tips().iter().map(|hash| hash.to_string()).collect(),
+                            });
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -1,19 +1,40 @@
-use crate::block::Block;
-use std::collections::HashMap;
+use crate::block::{Block, Header};
+use std::collections::{HashMap, HashSet};
 use crate::crypto::hash::{H256, Hashable};
-use log::debug;
+use log::{debug, error, info, warn};
 use crate::block::test::generate_static_block;
+use crate::storage::{BlockStore, MemoryStore};
+use crate::consensus::{self, Params};
+use std::io;
 
 pub struct Blockchain {
     pub data: HashMap<H256, BlockStruct>,
     pub tip_hash: H256,
     pub tip_height: u32,
     pub total_size: u32,
+    pub params: Params,
+    genesis_hash: H256,
+    /// Hashes of the longest chain, indexed by height
+    longest_chain: Vec<H256>,
+    /// Headers of every block, and of blocks whose content has not been downloaded yet
+    headers: HashMap<H256, HeaderStruct>,
+    /// Last header of the header chain with the most cumulative work
+    best_header: H256,
+    store: Box<dyn BlockStore>,
+}
+
+pub struct HeaderStruct {
+    pub header: Header,
+    pub height: u32,
+    /// Total work of the header chain ending at this header
+    pub chain_work: H256,
 }
 
 pub struct BlockStruct {
     pub block_content: Block,
-    block_height: u32,
+    pub block_height: u32,
+    /// Total work of the chain ending at this block, derived from each block's difficulty
+    pub chain_work: H256,
 }
 
 
@@ -21,6 +42,26 @@ pub struct BlockStruct {
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStore), Params::default())
+    }
+
+    /// Open a blockchain with the given consensus parameters backed by `store`, reloading every
+    /// block persisted in it
+    pub fn open(mut store: Box<dyn BlockStore>, params: Params) -> io::Result<Self> {
+        let stored_blocks = store.load()?;
+        let mut blockchain = Self::with_store(store, params);
+        for block in stored_blocks.iter() {
+            if !blockchain.data.contains_key(&block.header.parent) {
+                warn!("Stored block {} has an unknown parent, skipping", block.hash());
+                continue;
+            }
+            blockchain.insert(block);
+        }
+        info!("Reloaded {} blocks, tip {} at height {}", blockchain.total_size, blockchain.tip_hash, blockchain.tip_height);
+        Ok(blockchain)
+    }
+
+    fn with_store(store: Box<dyn BlockStore>, params: Params) -> Self {
         // In part 3, initiate all blockchain genesis being the same.
         // let mut rng = rand::thread_rng();
         // let root_array:[u8; 32]=[rng.gen(); 32];
@@ -32,20 +73,139 @@ impl Blockchain {
         let genesis_block: Block = generate_static_block(&root_hash);
 
         let mut data_new = HashMap::new();
-        data_new.insert(Hashable::hash(&genesis_block), BlockStruct{block_content: genesis_block.clone(), block_height: 0});
-        Blockchain{data: data_new, tip_hash: Hashable::hash(&genesis_block), tip_height: 0, total_size: 0}
+        let genesis_work = genesis_block.header.difficulty.work();
+        data_new.insert(Hashable::hash(&genesis_block), BlockStruct{block_content: genesis_block.clone(), block_height: 0, chain_work: genesis_work});
+        let mut headers = HashMap::new();
+        headers.insert(Hashable::hash(&genesis_block), HeaderStruct{header: genesis_block.header.clone(), height: 0, chain_work: genesis_work});
+        Blockchain{data: data_new, tip_hash: Hashable::hash(&genesis_block), tip_height: 0, total_size: 0, params,
+            genesis_hash: Hashable::hash(&genesis_block), longest_chain: vec![Hashable::hash(&genesis_block)],
+            headers, best_header: Hashable::hash(&genesis_block), store}
     }
 
-    /// Insert a block into blockchain
+    /// Insert a block into blockchain. The tip moves to the chain with the most cumulative work;
+    /// on equal work the chain whose tip has the lower hash wins, so all nodes agree on the tip.
     pub fn insert(&mut self, block: &Block) {
         debug!("BCInsertOK: {}", self.data.contains_key(&block.header.parent));
-        let this_height =(self.data[&block.header.parent]).block_height+1;
-        self.data.insert(Hashable::hash(block), BlockStruct{block_content: (*block).clone(), block_height: this_height});
+        let hash = Hashable::hash(block);
+        let parent = &self.data[&block.header.parent];
+        let this_height = parent.block_height+1;
+        let this_work = parent.chain_work.saturating_add(&block.header.difficulty.work());
+        if let Err(e) = self.store.put(block, this_height) {
+            error!("Failed to persist block {}: {}", hash, e);
+        }
+        self.data.insert(hash, BlockStruct{block_content: (*block).clone(), block_height: this_height, chain_work: this_work});
         self.total_size = self.total_size + 1;
-        if this_height > self.tip_height {
-            self.tip_height = self.tip_height+1;
-            self.tip_hash =Hashable::hash(block);
+        if !self.headers.contains_key(&hash) {
+            self.insert_header(&block.header);
+        }
+        let tip_work = self.data[&self.tip_hash].chain_work;
+        if this_work > tip_work || (this_work == tip_work && hash < self.tip_hash) {
+            let (leave, enter) = self.reorg_path(&self.tip_hash, &hash);
+            self.longest_chain.truncate(self.longest_chain.len() - leave.len());
+            self.longest_chain.extend(enter);
+            self.tip_height = this_height;
+            self.tip_hash = hash;
+        }
+    }
+
+    /// Insert a header whose parent header is known, ahead of its block's content. The best header
+    /// moves to the header chain with the most cumulative work, with the same tie-break as blocks.
+    pub fn insert_header(&mut self, header: &Header) {
+        let hash = header.hash();
+        let parent = &self.headers[&header.parent];
+        let height = parent.height + 1;
+        let chain_work = parent.chain_work.saturating_add(&header.difficulty.work());
+        self.headers.insert(hash, HeaderStruct{header: header.clone(), height, chain_work});
+        let best_work = self.headers[&self.best_header].chain_work;
+        if chain_work > best_work || (chain_work == best_work && hash < self.best_header) {
+            self.best_header = hash;
+        }
+    }
+
+    /// Check whether a header is known, with or without its block's content
+    pub fn has_header(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Get the last header of the header chain with the most work
+    pub fn best_header(&self) -> H256 {
+        self.best_header
+    }
+
+    /// Get the height of a known header
+    pub fn header_height(&self, hash: &H256) -> Option<u32> {
+        self.headers.get(hash).map(|h| h.height)
+    }
+
+    /// Get a block locator for the header chain ending at `hash`: the hashes of the ten last
+    /// headers, then of headers exponentially further apart, ending with the genesis block
+    pub fn locator(&self, hash: &H256) -> Vec<H256> {
+        let mut height = match self.header_height(hash) {
+            Some(height) => height,
+            None => return vec![self.genesis_hash],
+        };
+        let mut locator = Vec::new();
+        let mut step = 1;
+        loop {
+            locator.push(self.ancestor(hash, height).unwrap());
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Get up to `max` headers of the longest chain following the first locator hash found in it
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let fork = locator.iter().find(|hash| self.in_longest_chain(hash)).copied().unwrap_or(self.genesis_hash);
+        let start = self.data[&fork].block_height as usize + 1;
+        self.longest_chain.iter().skip(start).take(max)
+            .map(|hash| self.data[hash].block_content.header.clone())
+            .collect()
+    }
+
+    /// Get the hashes of the blocks on the best header chain whose content is missing, lowest first
+    pub fn missing_blocks(&self) -> Vec<H256> {
+        let mut missing = Vec::new();
+        let mut cursor = self.best_header;
+        while !self.data.contains_key(&cursor) {
+            missing.push(cursor);
+            cursor = self.headers[&cursor].header.parent;
+        }
+        missing.reverse();
+        missing
+    }
+
+    /// Forget the header of a block that turned out to be invalid, along with all headers building
+    /// on it, and move the best header back to the best remaining header chain
+    pub fn invalidate_header(&mut self, hash: &H256) {
+        if self.data.contains_key(hash) || !self.headers.contains_key(hash) {
+            return;
+        }
+        let mut by_height: Vec<(u32, H256)> = self.headers.iter().map(|(h, header)| (header.height, *h)).collect();
+        by_height.sort_unstable();
+        let mut invalid = vec![*hash];
+        for (_, h) in by_height {
+            if invalid.contains(&self.headers[&h].header.parent) {
+                invalid.push(h);
+            }
+        }
+        for h in invalid.iter() {
+            self.headers.remove(h);
         }
+        // most work first, and the lower hash on equal work
+        self.best_header = *self.headers.iter()
+            .max_by(|(ha, a), (hb, b)| a.chain_work.cmp(&b.chain_work).then(hb.cmp(ha)))
+            .unwrap().0;
+    }
+
+    /// Get the total work of the chain ending at a block
+    pub fn chain_work(&self, hash: &H256) -> Option<H256> {
+        self.data.get(hash).map(|b| b.chain_work)
     }
 
     /// Get the last block's hash of the longest chain
@@ -53,18 +213,149 @@ impl Blockchain {
         self.tip_hash
     }
 
-    /// Get the last block's hash of the longest chain
-    #[cfg(any(test, test_utilities))]
+    /// Get the path between two blocks through their common ancestor: the blocks to leave on
+    /// `from`'s side (starting at `from`) and the blocks to enter on `to`'s side (ending at `to`)
+    pub fn reorg_path(&self, from: &H256, to: &H256) -> (Vec<H256>, Vec<H256>) {
+        let ancestor = self.common_ancestor(from, to).unwrap();
+        let leave = self.ancestors_until(from, &ancestor);
+        let mut enter = self.ancestors_until(to, &ancestor);
+        enter.reverse();
+        (leave, enter)
+    }
+
+    /// Hashes from `hash` back to (excluding) its ancestor `ancestor`
+    fn ancestors_until(&self, hash: &H256, ancestor: &H256) -> Vec<H256> {
+        let mut cursor = *hash;
+        let mut ret_hashes = Vec::new();
+        while cursor != *ancestor {
+            ret_hashes.push(cursor);
+            cursor = self.data[&cursor].block_content.header.parent;
+        }
+        ret_hashes
+    }
+
+    /// Get the latest block that both blocks descend from (a block descends from itself)
+    pub fn common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        if !self.data.contains_key(a) || !self.data.contains_key(b) {
+            return None;
+        }
+        let mut a = *a;
+        let mut b = *b;
+        while self.data[&a].block_height > self.data[&b].block_height {
+            a = self.data[&a].block_content.header.parent;
+        }
+        while self.data[&b].block_height > self.data[&a].block_height {
+            b = self.data[&b].block_content.header.parent;
+        }
+        while a != b {
+            a = self.data[&a].block_content.header.parent;
+            b = self.data[&b].block_content.header.parent;
+        }
+        Some(a)
+    }
+
+    /// Get the ancestor of a block or header at the given height, walking back through the headers
+    pub fn ancestor(&self, hash: &H256, height: u32) -> Option<H256> {
+        let mut cursor = *hash;
+        let mut cursor_height = self.header_height(hash)?;
+        if height > cursor_height {
+            return None;
+        }
+        while cursor_height > height {
+            cursor = self.headers[&cursor].header.parent;
+            cursor_height -= 1;
+        }
+        Some(cursor)
+    }
+
+    /// Get the difficulty a block built on `parent` must have. It is the parent's difficulty,
+    /// except every `retarget_interval` blocks, when it is retargeted by how long the last
+    /// interval took.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let parent_header = &self.headers[parent];
+        let height = parent_header.height + 1;
+        let interval = self.params.retarget_interval;
+        if !height.is_multiple_of(interval) {
+            return parent_header.header.difficulty;
+        }
+        let first = self.ancestor(parent, height - interval).unwrap();
+        let first_timestamp = self.headers[&first].header.timestamp;
+        let actual_timespan = parent_header.header.timestamp.saturating_sub(first_timestamp);
+        consensus::retarget(&self.params, &parent_header.header.difficulty, actual_timespan)
+    }
+
+    /// Get the median timestamp of a block and its ancestors, up to `median_time_span` blocks
+    pub fn median_time_past(&self, hash: &H256) -> u128 {
+        let mut timestamps: Vec<u128> = Vec::new();
+        let mut cursor = *hash;
+        while let Some(header) = self.headers.get(&cursor) {
+            timestamps.push(header.header.timestamp);
+            if timestamps.len() == self.params.median_time_span {
+                break;
+            }
+            cursor = header.header.parent;
+        }
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// Get the height of a block, the genesis block being at height 0
+    pub fn height(&self, hash: &H256) -> Option<u32> {
+        self.data.get(hash).map(|b| b.block_height)
+    }
+
+    /// Get the hash of the block at `height` in the longest chain
+    pub fn hash_at_height(&self, height: u32) -> Option<H256> {
+        self.longest_chain.get(height as usize).copied()
+    }
+
+    /// Check whether a block is part of the longest chain
+    pub fn in_longest_chain(&self, hash: &H256) -> bool {
+        match self.height(hash) {
+            Some(height) => self.hash_at_height(height) == Some(*hash),
+            None => false,
+        }
+    }
+
+    /// Get the number of confirmations of a block: 1 for the tip, 0 for blocks off the longest
+    /// chain, and None for unknown blocks
+    pub fn confirmations(&self, hash: &H256) -> Option<u32> {
+        let height = self.height(hash)?;
+        if self.in_longest_chain(hash) {
+            Some(self.tip_height - height + 1)
+        } else {
+            Some(0)
+        }
+    }
+
+    /// Get the genesis block's hash
+    pub fn genesis(&self) -> H256 {
+        self.genesis_hash
+    }
+
+    /// Get all blocks' hashes of the longest chain, from the genesis block to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        unimplemented!()
+        self.longest_chain.clone()
+    }
+
+    /// Get the last blocks of the branches that forked off the longest chain
+    pub fn fork_tips(&self) -> Vec<H256> {
+        let parents: HashSet<H256> = self.data.values().map(|b| b.block_content.header.parent).collect();
+        self.data.keys()
+            .filter(|hash| !parents.contains(*hash) && !self.in_longest_chain(hash))
+            .copied()
+            .collect()
     }
 }
 
 #[cfg(any(test, test_utilities))]
 mod tests {
     use super::*;
+    use crate::block::Block;
     use crate::block::test::generate_random_block;
     use crate::crypto::hash::Hashable;
+    use crate::storage::FileStore;
+    use crate::storage::tests::temp_dir;
 
     #[test]
     fn insert_one() {
@@ -75,4 +366,186 @@ mod tests {
         assert_eq!(blockchain.tip(), block.hash());
 
     }
+
+    #[test]
+    fn longest_chain_queries() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let a2 = generate_random_block(&a1.hash());
+        let b2 = generate_random_block(&a1.hash());
+        let b3 = generate_random_block(&b2.hash());
+        blockchain.insert(&a1);
+        blockchain.insert(&a2);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, a1.hash(), a2.hash()]);
+        assert!(blockchain.fork_tips().is_empty());
+        blockchain.insert(&b2);
+        blockchain.insert(&b3);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, a1.hash(), b2.hash(), b3.hash()]);
+        assert_eq!(blockchain.hash_at_height(2), Some(b2.hash()));
+        assert_eq!(blockchain.fork_tips(), vec![a2.hash()]);
+        assert_eq!(blockchain.hash_at_height(4), None);
+        assert_eq!(blockchain.height(&a2.hash()), Some(2));
+        assert_eq!(blockchain.confirmations(&a1.hash()), Some(3));
+        assert_eq!(blockchain.confirmations(&a2.hash()), Some(0));
+        assert_eq!(blockchain.confirmations(&genesis_hash), Some(4));
+        assert_eq!(blockchain.confirmations(&H256::from([1; 32])), None);
+        assert_eq!(blockchain.common_ancestor(&a2.hash(), &b3.hash()), Some(a1.hash()));
+        assert_eq!(blockchain.common_ancestor(&a1.hash(), &b3.hash()), Some(a1.hash()));
+        assert_eq!(blockchain.common_ancestor(&a2.hash(), &H256::from([1; 32])), None);
+    }
+
+    #[test]
+    fn locator_and_headers_after() {
+        let mut blockchain = Blockchain::new();
+        let mut chain = vec![blockchain.genesis()];
+        for _ in 0..25 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block);
+            chain.push(block.hash());
+        }
+        let heights: Vec<usize> = (16..=25).rev().chain(vec![14, 10, 2, 0]).collect();
+        let expected: Vec<H256> = heights.iter().map(|h| chain[*h]).collect();
+        assert_eq!(blockchain.locator(&blockchain.tip()), expected);
+        assert_eq!(blockchain.locator(&blockchain.genesis()), vec![blockchain.genesis()]);
+
+        // the first locator hash in the longest chain is the fork point
+        let side = generate_random_block(&chain[3]);
+        blockchain.insert(&side);
+        let locator = vec![H256::from([1; 32]), side.hash(), chain[5], chain[0]];
+        let headers = blockchain.headers_after(&locator, 3);
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(hashes, chain[6..9].to_vec());
+        assert_eq!(blockchain.headers_after(&[H256::from([1; 32])], 100).len(), 25);
+        assert!(blockchain.headers_after(&[blockchain.tip()], 100).is_empty());
+    }
+
+    #[test]
+    fn headers_ahead_of_blocks() {
+        let mut source = Blockchain::new();
+        let mut blocks = Vec::new();
+        let mut parent = source.genesis();
+        for _ in 0..5 {
+            let block = generate_random_block(&parent);
+            source.insert(&block);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        let mut blockchain = Blockchain::new();
+        for block in blocks.iter() {
+            blockchain.insert_header(&block.header);
+        }
+        let hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+        assert_eq!(blockchain.best_header(), hashes[4]);
+        assert_eq!(blockchain.tip(), blockchain.genesis());
+        assert_eq!(blockchain.missing_blocks(), hashes);
+        assert_eq!(blockchain.locator(&blockchain.best_header()).len(), 6);
+
+        blockchain.insert(&blocks[0]);
+        assert_eq!(blockchain.tip(), hashes[0]);
+        assert_eq!(blockchain.missing_blocks(), hashes[1..].to_vec());
+
+        // an invalid block takes the headers building on it along
+        blockchain.invalidate_header(&hashes[2]);
+        assert!(!blockchain.has_header(&hashes[4]));
+        assert_eq!(blockchain.best_header(), hashes[1]);
+        assert_eq!(blockchain.missing_blocks(), vec![hashes[1]]);
+        blockchain.invalidate_header(&hashes[1]);
+        assert_eq!(blockchain.best_header(), hashes[0]);
+        assert!(blockchain.missing_blocks().is_empty());
+    }
+
+    fn block_with_difficulty(parent: &H256, first_byte: u8) -> Block {
+        let mut block = generate_random_block(parent);
+        let mut difficulty = [0xff; 32];
+        difficulty[0] = first_byte;
+        block.header.difficulty = difficulty.into();
+        block
+    }
+
+    #[test]
+    fn most_work_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        // three easy blocks (work 1 each) against two blocks of work 2 each
+        let a1 = block_with_difficulty(&genesis_hash, 0xff);
+        let a2 = block_with_difficulty(&a1.hash(), 0xff);
+        let a3 = block_with_difficulty(&a2.hash(), 0xff);
+        let b1 = block_with_difficulty(&genesis_hash, 0x7f);
+        let b2 = block_with_difficulty(&b1.hash(), 0x7f);
+        for block in [&a1, &a2, &a3, &b1].iter() {
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.tip(), a3.hash());
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert_eq!(blockchain.tip_height, 2);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, b1.hash(), b2.hash()]);
+        // a single harder block outweighs several easy ones, even when jumping heights
+        let c1 = block_with_difficulty(&a3.hash(), 0x0f);
+        blockchain.insert(&c1);
+        assert_eq!(blockchain.tip(), c1.hash());
+        assert_eq!(blockchain.tip_height, 4);
+        assert_eq!(blockchain.hash_at_height(1), Some(a1.hash()));
+    }
+
+    #[test]
+    fn equal_work_tie_break() {
+        let genesis_hash = Blockchain::new().tip();
+        let x = block_with_difficulty(&genesis_hash, 0x7f);
+        let y = block_with_difficulty(&genesis_hash, 0x7f);
+        let lower = if x.hash() < y.hash() { x.hash() } else { y.hash() };
+        for order in [[&x, &y], [&y, &x]].iter() {
+            let mut blockchain = Blockchain::new();
+            blockchain.insert(order[0]);
+            blockchain.insert(order[1]);
+            assert_eq!(blockchain.tip(), lower);
+        }
+    }
+
+    #[test]
+    fn difficulty_retarget() {
+        let params = Params{retarget_interval: 4, target_block_time: 1_000, ..Params::default()};
+        let mut blockchain = Blockchain::open(Box::new(MemoryStore), params).unwrap();
+        let genesis = blockchain.genesis();
+        let genesis_difficulty = blockchain.data[&genesis].block_content.header.difficulty;
+        let genesis_timestamp = blockchain.data[&genesis].block_content.header.timestamp;
+        // blocks 1 to 3 come twice as fast as they should
+        let mut parent = genesis;
+        for i in 1..4 {
+            assert_eq!(blockchain.next_difficulty(&parent), genesis_difficulty);
+            let mut block = generate_random_block(&parent);
+            block.header.difficulty = genesis_difficulty;
+            block.header.timestamp = genesis_timestamp + 500 * i;
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        // block 4 retargets by the 1500ms between blocks 0 and 3 against 4 * 1000ms, like Bitcoin
+        let mut expected = [0u8; 32];
+        expected[1] = 24;
+        assert_eq!(blockchain.next_difficulty(&parent), H256::from(expected));
+    }
+
+    #[test]
+    fn reopen_store() {
+        let dir = temp_dir("blockchain-reopen");
+        let (tip, height) = {
+            let mut blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap()), Params::default()).unwrap();
+            let genesis_hash = blockchain.tip();
+            let mut parent = genesis_hash;
+            for _ in 0..5 {
+                let block = generate_random_block(&parent);
+                blockchain.insert(&block);
+                parent = block.hash();
+            }
+            // a side branch off the genesis block must not move the tip
+            blockchain.insert(&generate_random_block(&genesis_hash));
+            (blockchain.tip(), blockchain.tip_height)
+        };
+        let blockchain = Blockchain::open(Box::new(FileStore::open(&dir).unwrap()), Params::default()).unwrap();
+        assert_eq!(blockchain.tip(), tip);
+        assert_eq!(blockchain.tip_height, height);
+        assert_eq!(blockchain.total_size, 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,134 @@
+use crate::crypto::hash::H256;
+use std::cmp;
+
+/// Consensus parameters. Every node of a network must run with the same values.
+#[derive(Debug, Clone)]
+pub struct Params {
+    /// Number of blocks between two difficulty adjustments
+    pub retarget_interval: u32,
+    /// Desired time between two blocks, in milliseconds
+    pub target_block_time: u64,
+    /// Bound on how much a single adjustment can change the target, in either direction
+    pub max_adjustment: u64,
+    /// The easiest target allowed, which is also the genesis block's difficulty
+    pub max_target: H256,
+    /// Largest allowed serialized block, in bytes
+    pub max_block_size: usize,
+    /// How far a block's timestamp may be ahead of the local clock, in milliseconds
+    pub max_future_block_time: u64,
+    /// Number of previous blocks whose median timestamp a new block must exceed
+    pub median_time_span: usize,
+    /// Value a coinbase may create at height 1, before any halving
+    pub initial_subsidy: u64,
+    /// Number of blocks after which the subsidy halves
+    pub halving_interval: u32,
+    /// Number of blocks a coinbase output must be buried under before it can be spent
+    pub coinbase_maturity: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        let mut max_target = [0u8; 32];
+        max_target[1] = 64;
+        Params {
+            retarget_interval: 100,
+            target_block_time: 10_000,
+            max_adjustment: 4,
+            max_target: max_target.into(),
+            max_block_size: 1_000_000,
+            max_future_block_time: 2 * 60 * 60 * 1000,
+            median_time_span: 11,
+            initial_subsidy: 50,
+            halving_interval: 10_000,
+            coinbase_maturity: 100,
+        }
+    }
+}
+
+/// Scale `old_target` by how long the last `retarget_interval` blocks actually took compared to
+/// how long they should have taken. The measured timespan is clamped to within a factor of
+/// `max_adjustment`, and the result never gets easier than `max_target`.
+pub fn retarget(params: &Params, old_target: &H256, actual_timespan: u128) -> H256 {
+    let expected = params.retarget_interval as u64 * params.target_block_time;
+    let min_timespan = cmp::max(expected / params.max_adjustment, 1);
+    let max_timespan = expected.saturating_mul(params.max_adjustment);
+    let actual = cmp::min(cmp::max(actual_timespan, min_timespan as u128), max_timespan as u128) as u64;
+
+    // old_target * actual / expected, split as (q * expected + r) * actual / expected so that
+    // nothing overflows unless the result itself does
+    let (quotient, remainder) = old_target.div_rem(&H256::from_u64(expected));
+    let remainder_part = remainder.checked_mul_u64(actual).unwrap().div_u64(expected);
+    let new_target = quotient
+        .checked_mul_u64(actual)
+        .and_then(|x| x.checked_add(&remainder_part))
+        .unwrap_or_else(H256::max_value);
+    cmp::min(new_target, params.max_target)
+}
+
+/// The new coins a block at `height` may create, on top of the fees of its transactions
+pub fn block_subsidy(params: &Params, height: u32) -> u64 {
+    let halvings = height / params.halving_interval;
+    if halvings >= 64 {
+        return 0;
+    }
+    params.initial_subsidy >> halvings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Params {
+        Params {
+            retarget_interval: 10,
+            target_block_time: 1_000,
+            max_adjustment: 4,
+            max_target: H256::max_value(),
+            ..Params::default()
+        }
+    }
+
+    fn target(first_byte: u8) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[1] = first_byte;
+        bytes.into()
+    }
+
+    #[test]
+    fn on_schedule() {
+        assert_eq!(retarget(&params(), &target(64), 10_000), target(64));
+        assert_eq!(retarget(&params(), &target(64), 20_000), target(128));
+        assert_eq!(retarget(&params(), &target(64), 5_000), target(32));
+    }
+
+    #[test]
+    fn steep_hash_rate_changes() {
+        // a hundred times more hash rate: only four times harder
+        assert_eq!(retarget(&params(), &target(64), 100), target(16));
+        // blocks with the same or decreasing timestamps
+        assert_eq!(retarget(&params(), &target(64), 0), target(16));
+        // a hundred times less hash rate: only four times easier
+        assert_eq!(retarget(&params(), &target(16), 1_000_000), target(64));
+    }
+
+    #[test]
+    fn capped_at_max_target() {
+        let mut params = params();
+        params.max_target = target(100);
+        assert_eq!(retarget(&params, &target(64), 40_000), target(100));
+        // near the top of the range the product overflows 256 bits
+        params.max_target = H256::max_value();
+        assert_eq!(retarget(&params, &H256::max_value(), 40_000), H256::max_value());
+    }
+
+    #[test]
+    fn subsidy_halving() {
+        let params = Params{initial_subsidy: 50, halving_interval: 10, ..Params::default()};
+        assert_eq!(block_subsidy(&params, 1), 50);
+        assert_eq!(block_subsidy(&params, 9), 50);
+        assert_eq!(block_subsidy(&params, 10), 25);
+        assert_eq!(block_subsidy(&params, 35), 6);
+        assert_eq!(block_subsidy(&params, 60), 0);
+        assert_eq!(block_subsidy(&params, u32::MAX), 0);
+    }
+}
@@ -45,6 +45,19 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+/// Parse the 64 hex digits printed by `Display`
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s)?;
+        if bytes.len() != 32 {
+            return Err(hex::FromHexError::InvalidStringLength);
+        }
+        Ok(H256::from(&bytes[..]))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -116,6 +129,149 @@ impl PartialOrd for H256 {
 }
 
 
+/// Arithmetic on H256 values read as big-endian 256-bit unsigned integers, e.g. PoW targets.
+impl H256 {
+    /// The largest value, 2^256 - 1
+    pub fn max_value() -> H256 {
+        H256([0xff; 32])
+    }
+
+    /// Four 64-bit limbs, most significant first
+    fn to_limbs(self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_be_bytes(self.0[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: [u64; 4]) -> H256 {
+        let mut buffer = [0u8; 32];
+        for (i, limb) in limbs.iter().enumerate() {
+            buffer[i * 8..i * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        H256(buffer)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        (self.0[31 - i / 8] >> (i % 8)) & 1 == 1
+    }
+
+    fn wrapping_shl1(self) -> H256 {
+        let mut limbs = self.to_limbs();
+        for i in 0..4 {
+            let carry = if i < 3 { limbs[i + 1] >> 63 } else { 0 };
+            limbs[i] = (limbs[i] << 1) | carry;
+        }
+        H256::from_limbs(limbs)
+    }
+
+    fn overflowing_add(self, other: H256) -> (H256, bool) {
+        let (a, b) = (self.to_limbs(), other.to_limbs());
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (sum, c1) = a[i].overflowing_add(b[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            limbs[i] = sum;
+            carry = c1 || c2;
+        }
+        (H256::from_limbs(limbs), carry)
+    }
+
+    fn overflowing_sub(self, other: H256) -> (H256, bool) {
+        let (a, b) = (self.to_limbs(), other.to_limbs());
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (diff, b1) = a[i].overflowing_sub(b[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            limbs[i] = diff;
+            borrow = b1 || b2;
+        }
+        (H256::from_limbs(limbs), borrow)
+    }
+
+    pub fn checked_add(&self, other: &H256) -> Option<H256> {
+        match self.overflowing_add(*other) {
+            (sum, false) => Some(sum),
+            (_, true) => None,
+        }
+    }
+
+    pub fn saturating_add(&self, other: &H256) -> H256 {
+        self.checked_add(other).unwrap_or_else(H256::max_value)
+    }
+
+    pub fn checked_sub(&self, other: &H256) -> Option<H256> {
+        match self.overflowing_sub(*other) {
+            (diff, false) => Some(diff),
+            (_, true) => None,
+        }
+    }
+
+    pub fn checked_mul_u64(&self, rhs: u64) -> Option<H256> {
+        let a = self.to_limbs();
+        let mut limbs = [0u64; 4];
+        let mut carry: u128 = 0;
+        for i in (0..4).rev() {
+            let product = a[i] as u128 * rhs as u128 + carry;
+            limbs[i] = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            return None;
+        }
+        Some(H256::from_limbs(limbs))
+    }
+
+    /// Quotient and remainder of the division by `rhs`. Panics if `rhs` is zero.
+    pub fn div_rem(&self, rhs: &H256) -> (H256, H256) {
+        assert!(!rhs.is_zero(), "division by zero");
+        let mut quotient = [0u8; 32];
+        let mut remainder = H256::default();
+        for i in (0..256).rev() {
+            // the remainder is below rhs, so a carry out of the shift means it now exceeds rhs
+            let carry = remainder.bit(255);
+            remainder = remainder.wrapping_shl1();
+            if self.bit(i) {
+                remainder.0[31] |= 1;
+            }
+            if carry || remainder >= *rhs {
+                remainder = remainder.overflowing_sub(*rhs).0;
+                quotient[31 - i / 8] |= 1 << (i % 8);
+            }
+        }
+        (H256(quotient), remainder)
+    }
+
+    pub fn div_u64(&self, rhs: u64) -> H256 {
+        self.div_rem(&H256::from_u64(rhs)).0
+    }
+
+    pub fn from_u64(value: u64) -> H256 {
+        H256::from_limbs([0, 0, 0, value])
+    }
+
+    /// The expected number of hashes needed to find one at or below this target,
+    /// i.e. 2^256 / (target + 1)
+    pub fn work(&self) -> H256 {
+        match self.checked_add(&H256::from_u64(1)) {
+            // (2^256 - target - 1) / (target + 1) + 1, which avoids representing 2^256
+            Some(divisor) => {
+                let inverted = H256::max_value().overflowing_sub(*self).0;
+                inverted.div_rem(&divisor).0.saturating_add(&H256::from_u64(1))
+            }
+            None => H256::from_u64(1),
+        }
+    }
+}
+
+
 /// A H160 hash.
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy, Debug)]
 pub struct H160([u8; 20]); // big endian u160
@@ -161,6 +317,41 @@ pub mod tests {
     use super::H256;
     use rand::Rng;
 
+    #[test]
+    fn arithmetic() {
+        let a = H256::from(hex!("00000000000000000000000000000001ffffffffffffffffffffffffffffffff"));
+        let one = H256::from_u64(1);
+        assert_eq!(a.checked_add(&one).unwrap(), H256::from(hex!("0000000000000000000000000000000200000000000000000000000000000000")));
+        assert_eq!(H256::default().checked_sub(&one), None);
+        assert_eq!(H256::max_value().checked_add(&one), None);
+        assert_eq!(a.checked_mul_u64(2).unwrap(), H256::from(hex!("00000000000000000000000000000003fffffffffffffffffffffffffffffffe")));
+        assert_eq!(H256::max_value().checked_mul_u64(2), None);
+        let (q, r) = a.checked_mul_u64(7).unwrap().checked_add(&H256::from_u64(5)).unwrap().div_rem(&a);
+        assert_eq!((q, r), (H256::from_u64(7), H256::from_u64(5)));
+        assert_eq!(H256::max_value().div_rem(&H256::max_value()), (one, H256::default()));
+        assert_eq!(H256::max_value().div_u64(1 << 32).checked_mul_u64(1 << 32).unwrap().checked_add(&H256::from_u64((1 << 32) - 1)).unwrap(), H256::max_value());
+    }
+
+    #[test]
+    fn work() {
+        assert_eq!(H256::max_value().work(), H256::from_u64(1));
+        let mut half = [0xff; 32];
+        half[0] = 0x7f;
+        assert_eq!(H256::from(half).work(), H256::from_u64(2));
+        let mut target = [0u8; 32];
+        target[3..].copy_from_slice(&[0xff; 29]);
+        // 2^232 - 1 as target takes 2^24 hashes on average
+        assert_eq!(H256::from(target).work(), H256::from_u64(1 << 24));
+    }
+
+    #[test]
+    fn parse() {
+        let hash = generate_random_hash();
+        assert_eq!(hash.to_string().parse::<H256>(), Ok(hash));
+        assert!("00ff".parse::<H256>().is_err());
+        assert!("zz".repeat(32).parse::<H256>().is_err());
+    }
+
     pub fn generate_random_hash() -> H256 {
         let mut rng = rand::thread_rng();
         let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
@@ -4,22 +4,26 @@ use log::info;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
 use std::thread;
 use std::sync::{Arc, Mutex};
 
-use crate::transaction::{self, Transaction, SignedTransaction, Mempool,Input, Output, StateChain, State};
+use crate::transaction::{self, Transaction, SignedTransaction, Mempool,Input, Output, StateChain, UtxoView};
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use crate::crypto::hash::{H256, H160, Hashable};
-use crate::blockchain::Blockchain;
 use crate::network::message::Message;
+use crate::miner::RunLimits;
 use rand::seq::SliceRandom; 
 
 
+/// How long to wait before trying again when there is no coin to spend
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
 enum ControlSignal {
-    Start(u64), // the number controls the lambda of interval between block generation
-    Exit,
+    Start(u64, RunLimits), // the number controls the lambda of interval between block generation
+    Pause,
+    Stop,
 }
 
 enum OperatingState {
@@ -33,11 +37,14 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
     statechain: Arc<Mutex<StateChain>>,
     keypair: Ed25519KeyPair,
     addresses: Vec<H160>,
+    limits: RunLimits,
+    run_begin: Instant,
+    /// Transactions generated in the current run
+    run_count: u64,
 }
 
 #[derive(Clone)]
@@ -50,7 +57,6 @@ pub struct Handle {
 
 pub fn new(
     server: &ServerHandle, 
-    blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
     statechain: &Arc<Mutex<StateChain>>,
     keypair: Ed25519KeyPair,
@@ -62,11 +68,13 @@ pub fn new(
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
-        blockchain: blockchain.clone(),
         mempool: Arc::clone(mempool),
         statechain: Arc::clone(statechain),
         keypair: keypair,
         addresses: addresses,
+        limits: RunLimits::default(),
+        run_begin: Instant::now(),
+        run_count: 0,
     };
 
     let handle = Handle {
@@ -77,14 +85,28 @@ pub fn new(
 }
 
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    /// Shut the generator down for good
+    pub fn stop(&self) {
+        // the generator may have shut down already
+        let _ = self.control_chan.send(ControlSignal::Stop);
+    }
+
+    /// Returns false if the generator was stopped for good
+    pub fn pause(&self) -> bool {
+        self.control_chan.send(ControlSignal::Pause).is_ok()
+    }
+
+    /// Returns false if the generator was stopped for good
+    pub fn start(&self, lambda: u64) -> bool {
+        self.start_limited(lambda, RunLimits::default())
     }
 
-    pub fn start(&self, lambda: u64) {
+    /// Start generating transactions until told otherwise or until one of the limits is reached. Returns
+    /// false if the generator was stopped for good.
+    pub fn start_limited(&self, lambda: u64, limits: RunLimits) -> bool {
         self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .send(ControlSignal::Start(lambda, limits))
+            .is_ok()
     }
 
 }
@@ -102,21 +124,74 @@ impl Context {
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
-            ControlSignal::Exit => {
+            ControlSignal::Stop => {
                 info!("Generator shutting down");
                 self.operating_state = OperatingState::ShutDown;
             }
-            ControlSignal::Start(i) => {
-                info!("Generator starting in continuous mode with lambda {}", i);
+            ControlSignal::Pause => {
+                info!("Generator paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Start(i, limits) => {
+                info!("Generator starting in continuous mode with lambda {} and limits {:?}", i, limits);
                 self.operating_state = OperatingState::Run(i);
+                self.limits = limits;
+                self.run_begin = Instant::now();
+                self.run_count = 0;
             }
         }
     }
 
-    fn generator_loop(&mut self) {
-        // main mining loop
-        let loop_begin = SystemTime::now();
+    /// Send a random coin of ours to another address. Returns false if there was no coin to
+    /// spend or the mempool refused the transaction.
+    fn generate_transaction(&self, self_address: H160, other_address: &[H160]) -> bool {
+        // Coinbase outputs are left alone, since their maturity depends on the blockchain height
+        let mut self_coins: Vec<(H256, usize, u64)> = Vec::new();
+        {
+            let statechain = self.statechain.lock().unwrap();
+            for (k, v) in statechain.state.data.iter(){
+                if v.1 != self_address || statechain.state.coinbase_height(k).is_some(){
+                    continue;
+                }
+                self_coins.push((k.0, k.1, v.0));
+            }
+        }
+        if self_coins.is_empty(){
+            return false;
+        }
+        // select a random address to send a random coin without more value than the coin,
+        // paying the minimum relay fee out of it
+        let recipient = other_address.choose(&mut rand::thread_rng()).unwrap().clone();
+        let input_coin = self_coins.choose(&mut rand::thread_rng()).unwrap().clone();
+        let make_tx = |fee: u64| {
+            let value = input_coin.2 - fee;
+            let input: Vec<Input> = vec![Input{tx_hash: input_coin.0, index: input_coin.1, coin_base: false}];
+            let output: Vec<Output> = vec![Output{address: recipient, value: value /2},
+                            Output{address: self_address, value: value - value /2}];
+            let t = Transaction{in_put: input, out_put: output};
+            SignedTransaction{transaction: t.clone(), signature: transaction::sign(&t, &self.keypair),
+                              pub_key: self.keypair.public_key().as_ref().to_vec()}
+        };
+
+        let mut mempool = self.mempool.lock().unwrap();
+        // the size does not depend on the values, so the fee can be sized on a fee-less draft
+        let fee = mempool.min_fee(bincode::serialized_size(&make_tx(0)).unwrap() as usize);
+        if input_coin.2 <= fee{
+            return false;
+        }
+        let signed_t = make_tx(fee);
+        let _insert_success = mempool.insert(&signed_t, fee);
+        std::mem::drop(mempool);
+        if _insert_success == false{
+            return false;
+        }
+        info!("{} sends {} value to {}", self_address,
+            signed_t.transaction.out_put[0].value, signed_t.transaction.out_put[0].address);
+        self.server.broadcast(Message::NewTransactionHashes(vec![signed_t.hash()]));
+        true
+    }
 
+    fn generator_loop(&mut self) {
         // Define self address and addresses of other peers in the network
         let self_address = H160::from(H256::from(self.keypair.public_key().as_ref()));
         let index = self.addresses.iter().position(|x| *x == self_address).unwrap();
@@ -127,8 +202,11 @@ impl Context {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    self.handle_control_signal(signal);
+                    match self.control_chan.recv() {
+                        Ok(signal) => self.handle_control_signal(signal),
+                        // every handle is gone, nothing can start the generator again
+                        Err(_) => self.operating_state = OperatingState::ShutDown,
+                    }
                     continue;
                 }
                 OperatingState::ShutDown => {
@@ -142,55 +220,70 @@ impl Context {
                     Err(TryRecvError::Disconnected) => panic!("Generator control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-
-            let loop_duration = SystemTime::now().duration_since(loop_begin).unwrap().as_secs();
-            if loop_duration > 50{
-                println!("Generating transactions finished");
-                break;
+            let lambda = match self.operating_state {
+                OperatingState::Run(i) => i,
+                // a signal paused or stopped the generator
+                _ => continue,
+            };
+            if self.limits.reached(self.run_begin, self.run_count) {
+                info!("Generator reached its run limit after {} transactions, pausing", self.run_count);
+                self.operating_state = OperatingState::Paused;
+                continue;
             }
-            
 
             // generate several transaction over time
-            let current_tip_hash = self.blockchain.lock().unwrap().tip_hash;
-            let current_state = State{data: self.statechain.lock().unwrap().data.get(&current_tip_hash).unwrap().clone()};
-            let mut self_coins: Vec<(H256, usize, u64)> = Vec::new();
-            for (k, v) in current_state.data{
-                if v.1 != self_address{
-                    continue;
+            if self.generate_transaction(self_address, &other_address) {
+                self.run_count += 1;
+                if lambda != 0 {
+                    thread::sleep(time::Duration::from_micros(lambda));
                 }
-                self_coins.push((k.0, k.1, v.0));
-            }
-            // select a random address to send a random coin without more value than the coin
-            let recipient = other_address.choose(&mut rand::thread_rng()).unwrap().clone();
-            let input_coin = self_coins.choose(&mut rand::thread_rng()).unwrap().clone();
-            let input: Vec<Input> = vec![Input{tx_hash: input_coin.0, index: input_coin.1, coin_base: false}];
-            let output: Vec<Output> = vec![Output{address: recipient, value: input_coin.2 /2}, 
-                            Output{address: self_address, value: input_coin.2 - input_coin.2 /2}];
-            let t = Transaction{in_put: input, out_put: output};
-            let signed_t = SignedTransaction{transaction: t.clone(), signature: transaction::sign(&t, &self.keypair),
-                                                            pub_key: self.keypair.public_key().as_ref().to_vec()};
-            
-            // TODO: actual transaction generation
-
-            let mut mempool = self.mempool.lock().unwrap();
-            let _insert_success = mempool.insert(&signed_t);
-            std::mem::drop(mempool);
-            if _insert_success == false{
-                continue;
+            } else {
+                thread::sleep(IDLE_INTERVAL);
             }
-            info!("{} sends {} value to {}", self_address,
-                signed_t.transaction.out_put[0].value, signed_t.transaction.out_put[0].address);
-            self.server.broadcast(Message::NewTransactionHashes(vec![signed_t.hash()]));
-
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
-                }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::Params;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::crypto::key_pair;
+    use crate::network::sim::{Config, Network};
+
+    #[test]
+    fn generator_pauses_at_run_limit() {
+        let network = Network::new(1, Params::default(), Config::default());
+        let node = &network.nodes[0];
+        let keypair = key_pair::random();
+        let address = H160::from(H256::from(keypair.public_key().as_ref()));
+        let (ctx, generator) = new(&node.server, &node.mempool, &node.statechain, keypair, vec![address, node.address]);
+        ctx.start();
+        let mempool_size = || node.mempool.lock().unwrap().total_size;
+
+        // with no coin to spend the generator idles until there is one
+        assert!(generator.start_limited(0, RunLimits { duration: None, count: Some(3) }));
+        thread::sleep(IDLE_INTERVAL * 2);
+        assert_eq!(mempool_size(), 0);
+        {
+            let mut statechain = node.statechain.lock().unwrap();
+            for _ in 0..8 {
+                statechain.state.data.insert((generate_random_hash(), 0), (10000, address));
             }
         }
+        assert!(network.wait_for(Duration::from_secs(10), |_| mempool_size() == 3));
+        // unspent coins are left, but the run is over
+        thread::sleep(IDLE_INTERVAL * 3);
+        assert_eq!(mempool_size(), 3);
+
+        generator.stop();
+        // the generator thread drops its end of the channel once it has shut down
+        let begin = Instant::now();
+        while generator.pause() {
+            assert!(begin.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!generator.start(0));
     }
 }
@@ -10,12 +10,16 @@ pub mod miner;
 pub mod network;
 pub mod transaction;
 pub mod generator;
+pub mod storage;
+pub mod consensus;
+pub mod validation;
+pub mod stratum;
 
 use clap::clap_app;
 use crossbeam::channel;
 use log::{error, info};
 use api::Server as ApiServer;
-use network::{server, worker};
+use network::{server, transport, worker};
 use crypto::key_pair;
 use std::net;
 use std::process;
@@ -36,8 +40,19 @@ fn main() {
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg stratum_addr: --stratum [ADDR] "Sets the IP address and the port to serve mining jobs to external hashers on")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outgoing connections to keep, dialing addresses learned from peers")
+     (@arg ban_time: --("ban-time") [SECS] default_value("86400") "Sets how long a misbehaving peer is banned")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for block nonces")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where blocks are persisted across restarts")
+     (@arg block_time: --("block-time") [MS] default_value("10000") "Sets the target time between blocks in milliseconds, must match all peers")
+     (@arg retarget_interval: --("retarget-interval") [INT] default_value("100") "Sets the number of blocks between difficulty adjustments, must match all peers")
+     (@arg min_relay_fee: --("min-relay-fee") [FEE] default_value("10") "Sets the minimum fee per 1000 bytes for a transaction to enter the mempool and be relayed")
+     (@arg mempool_size: --("mempool-size") [MB] default_value("32") "Sets the maximum size of the mempool in megabytes")
+     (@arg mempool_expiry: --("mempool-expiry") [SECS] default_value("3600") "Sets how long a transaction is kept in the mempool without being confirmed")
+     (@arg rbf: --rbf "Lets a transaction replace conflicting mempool transactions by paying a higher fee")
     )
     .get_matches();
 
@@ -65,18 +80,114 @@ fn main() {
             process::exit(1);
         });
 
+    // parse consensus parameters
+    let block_time = matches
+        .value_of("block_time")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block time: {}", e);
+            process::exit(1);
+        });
+    let retarget_interval = matches
+        .value_of("retarget_interval")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing retarget interval: {}", e);
+            process::exit(1);
+        });
+    if block_time == 0 || retarget_interval == 0 {
+        error!("Block time and retarget interval must be positive");
+        process::exit(1);
+    }
+    let params = consensus::Params {
+        target_block_time: block_time,
+        retarget_interval,
+        ..Default::default()
+    };
+
+    // start a new blockchain, or reload the persisted one. Note that this chain contains genesis block
+    let store: Box<dyn storage::BlockStore> = match matches.value_of("data_dir") {
+        Some(dir) => {
+            let store = storage::FileStore::open(dir).unwrap_or_else(|e| {
+                error!("Error opening data directory {}: {}", dir, e);
+                process::exit(1);
+            });
+            Box::new(store)
+        }
+        None => Box::new(storage::MemoryStore),
+    };
+    let blockchain = blockchain::Blockchain::open(store, params).unwrap_or_else(|e| {
+        error!("Error loading blocks: {}", e);
+        process::exit(1);
+    });
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    // start the p2p server, which announces our genesis and height to peers
+    let outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound connections: {}", e);
+            process::exit(1);
+        });
+    let ban_time = matches
+        .value_of("ban_time")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban time: {}", e);
+            process::exit(1);
+        });
+    let (server_ctx, server) = server::new(
+        p2p_addr,
+        msg_tx,
+        &blockchain,
+        outbound,
+        time::Duration::from_secs(ban_time),
+        Arc::new(transport::Tcp),
+    )
+    .unwrap();
     server_ctx.start().unwrap();
 
-    // start a new blockchain. Note that this chain contains genesis block
-    let blockchain = Arc::new(Mutex::new(blockchain::Blockchain::new()));
-
     // start a new mempool
-    let mempool = Arc::new(Mutex::new(transaction::Mempool::new()));
+    let min_relay_fee = matches
+        .value_of("min_relay_fee")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing minimum relay fee: {}", e);
+            process::exit(1);
+        });
+    let mempool_size = matches
+        .value_of("mempool_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool size: {}", e);
+            process::exit(1);
+        });
+    let mempool_expiry = matches
+        .value_of("mempool_expiry")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool expiry: {}", e);
+            process::exit(1);
+        });
+    let mempool_policy = transaction::MempoolPolicy {
+        min_relay_fee,
+        max_bytes: mempool_size * 1_000_000,
+        expiry: time::Duration::from_secs(mempool_expiry),
+        replace_by_fee: matches.is_present("rbf"),
+        ..Default::default()
+    };
+    let mempool = Arc::new(Mutex::new(transaction::Mempool::with_policy(mempool_policy)));
 
     // preparing 3 worker settings
     if Path::new("/home/hongpeng/Desktop/Spring20/ECE598/bitcoin_midterm/src/keys/1.key").exists() == false{
@@ -97,9 +208,10 @@ fn main() {
         initial_addresses.push(H160::from(initial_pubkey_hashes[i]));
     }
 
-    // Initial state ICO and start a new statechain
-    let statechain = Arc::new(Mutex::new(transaction::StateChain::new()));
-    statechain.lock().unwrap().insert(blockchain.lock().unwrap().tip_hash, transaction::ico3_proc(initial_pubkey_hashes.clone()));
+    // Initial state ICO and start a new statechain, then bring it up to any reloaded tip
+    let mut statechain = transaction::StateChain::new(blockchain.lock().unwrap().genesis(), transaction::ico3_proc(initial_pubkey_hashes.clone()));
+    statechain.sync(&blockchain.lock().unwrap());
+    let statechain = Arc::new(Mutex::new(statechain));
     info!("ICO Finished for this process");
     
     // Dispatching processes with corresponding keys
@@ -127,22 +239,43 @@ fn main() {
         &statechain,
         initial_addresses[(p2p_addr.port() % 1000) as usize]
     );
+    let orphans = Arc::clone(worker_ctx.orphans());
     worker_ctx.start();
 
     // start the miner
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
     let (miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
         &mempool,
         &statechain,
-        initial_addresses[(p2p_addr.port() % 1000) as usize]
+        initial_addresses[(p2p_addr.port() % 1000) as usize],
+        miner_threads,
     );
     miner_ctx.start();
 
+    // serve mining jobs to external hashers
+    if let Some(addr) = matches.value_of("stratum_addr") {
+        let addr = addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing stratum server address: {}", e);
+            process::exit(1);
+        });
+        stratum::start(addr, miner.assembler()).unwrap_or_else(|e| {
+            error!("Error starting stratum server: {}", e);
+            process::exit(1);
+        });
+    }
+
     // start the generator
     let (generator_ctx, generator) = generator::new(
         &server,
-        &blockchain,
         &mempool,
         &statechain,
         self_keypair,
@@ -150,49 +283,29 @@ fn main() {
     );
     generator_ctx.start();
 
-    // connect to known peers
+    // connect to known peers, and keep reconnecting to them
     if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
+        for peer in known_peers {
+            match peer.parse::<net::SocketAddr>() {
+                Ok(addr) => server.add_persistent_peer(addr),
+                Err(e) => error!("Error parsing peer address {}: {}", peer, e),
             }
-        });
+        }
     }
 
-
     // start the API server
     ApiServer::start(
         api_addr,
         &miner,
         &generator,
         &server,
+        &mempool,
+        &blockchain,
+        &statechain,
+        &orphans,
     );
 
     loop {
-        std::thread::park();
+        thread::park();
     }
 }
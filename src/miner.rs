@@ -1,26 +1,53 @@
 use crate::network::server::Handle as ServerHandle;
 
-use log::info;
+use log::{error, info};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::time;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use rand::{thread_rng, Rng};
 
 use crate::blockchain::Blockchain;
 use crate::block::{Block, Header, Content};
-use crate::transaction::{Mempool, State, StateChain};
+use crate::transaction::{Mempool, SignedTransaction, StateChain};
 use crate::crypto::merkle::MerkleTree;
-use crate::crypto::hash::{Hashable, H256, H160};
+use crate::crypto::hash::{Hashable, H160, H256};
 use crate::network::message::Message;
+use crate::network::compact::CompactBlock;
+use crate::consensus;
+use crate::validation::{self, BlockValidationError};
 
+/// Bytes of a block kept free for the header and the coinbase when selecting transactions
+const BLOCK_RESERVED_SIZE: usize = 1000;
+/// How often the miner checks for a new tip or a control signal while the search threads run
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Nonces a search thread tries between checks of the abort flag
+const HASH_BATCH: u64 = 1024;
 
 enum ControlSignal {
-    Start(u64), // the number controls the lambda of interval between block generation
-    Exit,
+    Start(u64, RunLimits), // the number controls the lambda of interval between block generation
+    Pause,
+    Stop,
+}
+
+/// Optional limits on a run of the miner or the generator, after which it pauses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunLimits {
+    /// How long to run for
+    pub duration: Option<Duration>,
+    /// How many blocks to mine, or transactions to generate
+    pub count: Option<u64>,
+}
+
+impl RunLimits {
+    /// Whether a run that began at `begin` and produced `count` items is over
+    pub fn reached(&self, begin: Instant, count: u64) -> bool {
+        self.duration.is_some_and(|duration| begin.elapsed() >= duration)
+            || self.count.is_some_and(|max| count >= max)
+    }
 }
 
 enum OperatingState {
@@ -33,33 +60,158 @@ pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
-    server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<Mempool>>,
-    statechain: Arc<Mutex<StateChain>>,
-    self_address: H160
+    assembler: Assembler,
+    /// Number of threads searching for a nonce
+    threads: usize,
+    hash_rate: Arc<HashRate>,
+    limits: RunLimits,
+    run_begin: Instant,
+    /// Blocks mined in the current run
+    run_count: u64,
+}
+
+/// Hashes computed by the search threads, and the rate over the last second
+#[derive(Default)]
+struct HashRate {
+    hashes: AtomicU64,
+    per_second: AtomicU64,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    hash_rate: Arc<HashRate>,
+    assembler: Assembler,
 }
 
+/// A block waiting for its proof of work: the header with a zero nonce, and the transactions
+/// starting with the coinbase
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub height: u32,
+    pub header: Header,
+    pub transactions: Vec<SignedTransaction>,
+}
 
+impl BlockTemplate {
+    /// The block solved with the given nonce and timestamp
+    pub fn block(&self, nonce: u32, timestamp: u128) -> Block {
+        let header = Header { nonce, timestamp, ..self.header.clone() };
+        Block { header, content: Content { content: self.transactions.clone() } }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitError {
+    /// The block is already in the blockchain
+    Duplicate,
+    Invalid(BlockValidationError),
+}
+
+/// Builds block templates on the tip and connects solved blocks, for the miner thread as well
+/// as external hashers
+#[derive(Clone)]
+pub struct Assembler {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    statechain: Arc<Mutex<StateChain>>,
+    self_address: H160,
+}
+
+impl Assembler {
+    pub fn tip(&self) -> H256 {
+        self.blockchain.lock().unwrap().tip()
+    }
+
+    /// A block on the tip paying the subsidy and fees to our address
+    pub fn template(&self) -> BlockTemplate {
+        let blockchain = self.blockchain.lock().unwrap();
+        let parent = blockchain.tip();
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let timestamp = std::cmp::max(now, blockchain.median_time_past(&parent) + 1);
+        let difficulty = blockchain.next_difficulty(&parent);
+        let height = blockchain.tip_height + 1;
+        let params = blockchain.params.clone();
+
+        // the statechain is kept at the blockchain tip, so this is the state of the parent
+        let mut state = self.statechain.lock().unwrap().state.clone();
+        std::mem::drop(blockchain);
+
+        // Adding real transaction implementations, best paying first, leaving room for the
+        // header and coinbase. They stay in the mempool until a block confirms them.
+        let tx_vec = {
+            let mut mempool = self.mempool.lock().unwrap();
+            mempool.expire(Instant::now());
+            mempool.select(params.max_block_size.saturating_sub(BLOCK_RESERVED_SIZE))
+        };
+
+        // state update and all the checks
+        let (accept_vec, _abort_vec, fees) = state.update(tx_vec, height, &params);
+
+        // the coinbase comes first and collects the subsidy plus all fees
+        let reward = consensus::block_subsidy(&params, height).saturating_add(fees);
+        let mut block_txs = vec![SignedTransaction::coinbase(height, self.self_address, reward)];
+        block_txs.extend(accept_vec);
+        let merkle_tree = MerkleTree::new(& block_txs);
+
+        let header = Header{parent: parent, nonce: 0, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_tree.root()};
+        BlockTemplate { height, header, transactions: block_txs }
+    }
+
+    /// Validate a solved block, connect it and announce it to our peers
+    pub fn submit(&self, block: &Block) -> Result<(), SubmitError> {
+        {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            if blockchain.data.contains_key(&block.hash()) {
+                return Err(SubmitError::Duplicate);
+            }
+            // the parent's state can only be built for blocks we have
+            if !blockchain.data.contains_key(&block.header.parent) {
+                return Err(SubmitError::Invalid(BlockValidationError::UnknownParent(block.header.parent)));
+            }
+            let mut statechain = self.statechain.lock().unwrap();
+            // the tip may have moved since the template was made, so validate against the
+            // parent's state
+            let validity = {
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+                let parent_state = statechain.state_at(&blockchain, &block.header.parent);
+                validation::validate_block(block, &blockchain, &*parent_state, now)
+            };
+            validity.map_err(SubmitError::Invalid)?;
+            blockchain.insert(block);
+            let (disconnected, connected) = statechain.sync(&blockchain);
+            self.mempool.lock().unwrap().sync(&blockchain, &statechain.state, &disconnected, &connected);
+            // log info for receiving transaction value
+            for signed_tx in block.content.content.iter(){
+                for output in signed_tx.transaction.out_put.iter(){
+                    if output.address != self.self_address{
+                        continue;
+                    }
+                    match signed_tx.sender() {
+                        Some(sender) => info!("{} receives {} value from {}", self.self_address, output.value, sender),
+                        None => info!("{} receives {} value from coinbase", self.self_address, output.value),
+                    }
+                }
+            }
+        }
+        self.server.broadcast(Message::CompactBlock(CompactBlock::new(block, rand::random())));
+        Ok(())
+    }
+}
 
 pub fn new(
     server: &ServerHandle, 
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
     statechain: &Arc<Mutex<StateChain>>,
-    self_address: H160
+    self_address: H160,
+    threads: usize,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
-
-    let ctx = Context {
-        control_chan: signal_chan_receiver,
-        operating_state: OperatingState::Paused,
+    let hash_rate = Arc::new(HashRate::default());
+    let assembler = Assembler {
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
@@ -67,26 +219,102 @@ pub fn new(
         self_address: self_address,
     };
 
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        operating_state: OperatingState::Paused,
+        assembler: assembler.clone(),
+        threads: threads.max(1),
+        hash_rate: Arc::clone(&hash_rate),
+        limits: RunLimits::default(),
+        run_begin: Instant::now(),
+        run_count: 0,
+    };
+
     let handle = Handle {
         control_chan: signal_chan_sender,
+        hash_rate,
+        assembler,
     };
 
     (ctx, handle)
 }
 
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    /// Shut the miner down for good
+    pub fn stop(&self) {
+        // the miner may have shut down already
+        let _ = self.control_chan.send(ControlSignal::Stop);
+    }
+
+    /// Returns false if the miner was stopped for good
+    pub fn pause(&self) -> bool {
+        self.control_chan.send(ControlSignal::Pause).is_ok()
+    }
+
+    /// Returns false if the miner was stopped for good
+    pub fn start(&self, lambda: u64) -> bool {
+        self.start_limited(lambda, RunLimits::default())
     }
 
-    pub fn start(&self, lambda: u64) {
+    /// Start mining until told otherwise or until one of the limits is reached. Returns
+    /// false if the miner was stopped for good.
+    pub fn start_limited(&self, lambda: u64, limits: RunLimits) -> bool {
         self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .send(ControlSignal::Start(lambda, limits))
+            .is_ok()
+    }
+
+    /// Hashes computed per second over the last second of mining
+    pub fn hash_rate(&self) -> u64 {
+        self.hash_rate.per_second.load(Ordering::Relaxed)
+    }
+
+    /// Builds templates for and accepts blocks from external hashers
+    pub fn assembler(&self) -> &Assembler {
+        &self.assembler
     }
 
 }
 
+/// The part of the nonce space search thread `index` of `threads` goes through
+fn nonce_range(index: usize, threads: usize) -> std::ops::Range<u64> {
+    let space = u64::from(u32::MAX) + 1;
+    let span = space / threads as u64;
+    let start = index as u64 * span;
+    if index + 1 == threads {
+        start..space
+    } else {
+        start..start + span
+    }
+}
+
+/// Look for a nonce in the thread's part of the nonce space that brings the header's hash under
+/// its difficulty, rolling the timestamp forward whenever the part is exhausted. Gives up once
+/// `abort` is set.
+fn search_nonces(mut header: Header, nonces: std::ops::Range<u64>, abort: &AtomicBool, hash_rate: &HashRate, found: &Sender<Header>) {
+    let mut count = 0;
+    loop {
+        for nonce in nonces.clone() {
+            count += 1;
+            if count == HASH_BATCH {
+                hash_rate.hashes.fetch_add(count, Ordering::Relaxed);
+                count = 0;
+                if abort.load(Ordering::Relaxed) {
+                    return;
+                }
+            }
+            header.nonce = nonce as u32;
+            if header.hash() <= header.difficulty {
+                hash_rate.hashes.fetch_add(count, Ordering::Relaxed);
+                let _ = found.send(header);
+                return;
+            }
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        header.timestamp = std::cmp::max(now, header.timestamp + 1);
+    }
+}
+
 impl Context {
     pub fn start(mut self) {
         thread::Builder::new()
@@ -100,33 +328,93 @@ impl Context {
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
-            ControlSignal::Exit => {
+            ControlSignal::Stop => {
                 info!("Miner shutting down");
                 self.operating_state = OperatingState::ShutDown;
             }
-            ControlSignal::Start(i) => {
-                info!("Miner starting in continuous mode with lambda {}", i);
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Start(i, limits) => {
+                info!("Miner starting in continuous mode with lambda {} and limits {:?}", i, limits);
                 self.operating_state = OperatingState::Run(i);
+                self.limits = limits;
+                self.run_begin = Instant::now();
+                self.run_count = 0;
+            }
+        }
+    }
+
+    /// Search for a nonce with all mining threads. Returns None if the tip moved away from the
+    /// header's parent, or a control signal stopped the miner, before one was found.
+    fn search(&mut self, header: Header, rate_sample: &mut (Instant, u64)) -> Option<Header> {
+        let abort = AtomicBool::new(false);
+        let (found_sender, found_receiver) = bounded(self.threads);
+        let threads = self.threads;
+        let hash_rate = Arc::clone(&self.hash_rate);
+        thread::scope(|scope| {
+            for index in 0..threads {
+                let (header, abort, hash_rate, found_sender) = (header.clone(), &abort, &*hash_rate, &found_sender);
+                scope.spawn(move || search_nonces(header, nonce_range(index, threads), abort, hash_rate, found_sender));
             }
+            let result = loop {
+                match found_receiver.recv_timeout(POLL_INTERVAL) {
+                    Ok(header) => break Some(header),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => unreachable!(),
+                }
+                self.sample_hash_rate(rate_sample);
+                if self.assembler.tip() != header.parent || self.limits.reached(self.run_begin, self.run_count) {
+                    break None;
+                }
+                if let Ok(signal) = self.control_chan.try_recv() {
+                    self.handle_control_signal(signal);
+                    if let OperatingState::Run(_) = self.operating_state {
+                        continue;
+                    }
+                    break None;
+                }
+            };
+            abort.store(true, Ordering::Relaxed);
+            result
+        })
+    }
+
+    /// Update the hash rate once a second has passed since the last sample
+    fn sample_hash_rate(&self, (time, hashes): &mut (Instant, u64)) {
+        let elapsed = time.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
         }
+        let total = self.hash_rate.hashes.load(Ordering::Relaxed);
+        let rate = (total - *hashes) as f64 / elapsed.as_secs_f64();
+        self.hash_rate.per_second.store(rate as u64, Ordering::Relaxed);
+        *time = Instant::now();
+        *hashes = total;
     }
 
     fn miner_loop(&mut self) {
         // main mining loop
-        
-        let loop_begin = SystemTime::now();
-        let mut block_mined = 0;
-        let tx_block: usize = 10; 
+        let mut rate_sample = (Instant::now(), 0);
 
         loop {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    self.handle_control_signal(signal);
+                    self.hash_rate.per_second.store(0, Ordering::Relaxed);
+                    match self.control_chan.recv() {
+                        Ok(signal) => self.handle_control_signal(signal),
+                        // every handle is gone, nothing can start the miner again
+                        Err(_) => self.operating_state = OperatingState::ShutDown,
+                    }
                     continue;
                 }
                 OperatingState::ShutDown => {
+                    let blockchain = self.assembler.blockchain.lock().unwrap();
+                    info!("BlockChain Length is {}", blockchain.total_size);
+                    info!("BlockChain height is {}", blockchain.tip_height);
+                    info!("BlockChain tip hash is {}", blockchain.tip_hash);
                     return;
                 }
                 _ => match self.control_chan.try_recv() {
@@ -137,77 +425,28 @@ impl Context {
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-            
-            let loop_duration = SystemTime::now().duration_since(loop_begin).unwrap().as_secs();
-            if loop_duration > 80{
-                info!("Blocks minded is {}", block_mined);
-                break;
+            // a signal may have paused or stopped the miner
+            if !matches!(self.operating_state, OperatingState::Run(_)) {
+                continue;
             }
-
-            // TODO: actual mining
-
-            let blockchain = self.blockchain.lock().unwrap();
-            let parent = blockchain.tip();
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
-            let difficulty = blockchain.data.get(&parent).unwrap().block_content.header.difficulty;
-
-            let current_tip_hash = blockchain.tip_hash;
-            std::mem::drop(blockchain);
-            let mut state = State{ data: self.statechain.lock().unwrap().data.get(& current_tip_hash).unwrap().clone()};
-
-            // Adding real transaction implementations
-            let mut mempool = self.mempool.lock().unwrap();
-            let tx_vec = mempool.retrieve_vec(tx_block);
-            // retrieve transactions until enough
-            if tx_vec.len() == 0 {
+            if self.limits.reached(self.run_begin, self.run_count) {
+                info!("Miner reached its run limit after mining {} blocks, pausing", self.run_count);
+                self.operating_state = OperatingState::Paused;
                 continue;
             }
 
-            // state update and all the checks
-            let mut state_copy = state.clone();
-            let (accept_vec, _abort_vec) = state_copy.update(tx_vec);
-
-            // cases when there are tx being aborted
-            // if _abort_vec.len() > 0{
-            //     mempool.insert_vec(_abort_vec);
-            // }
-            if accept_vec.len() == 0{
+            let template = self.assembler.template();
+            let header = match self.search(template.header.clone(), &mut rate_sample) {
+                Some(header) => header,
+                // the tip moved or the miner was told to stop, start over
+                None => continue,
+            };
+            let block = template.block(header.nonce, header.timestamp);
+            if let Err(e) = self.assembler.submit(&block) {
+                error!("Mined an invalid block {}: {:?}", block.hash(), e);
                 continue;
             }
-            state.update(accept_vec.clone());
-            std::mem::drop(mempool);
-            
-            let merkle_tree = MerkleTree::new(& accept_vec);
-
-            let mut rng = thread_rng();
-            loop{
-                let nonce = rng.gen();
-                let header = Header{parent: parent, nonce: nonce, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_tree.root()};
-                let content = Content{content: accept_vec.clone()};
-                let block = Block{header: header, content: content};
-                if Hashable::hash(&block) <= difficulty{
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut statechain = self.statechain.lock().unwrap();
-                    statechain.insert(block.hash(), state);
-                    blockchain.insert(&block);
-                    // log info for receiving transaction value  
-                    for signed_tx in block.clone().content.content{
-                        for output in signed_tx.transaction.out_put{
-                            if output.address != self.self_address{
-                                continue;
-                            }
-                            info!("{} receives {} value from {}", self.self_address,
-                                output.value, H160::from(H256::from(&signed_tx.pub_key[..])));
-                        }
-                    }
-                    block_mined += 1;
-                    self.server.broadcast(Message::NewBlockHashes(vec![Hashable::hash(&block)]));
-                    break;
-                } 
-            }
+            self.run_count += 1;
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
@@ -216,15 +455,84 @@ impl Context {
                 }
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::consensus::Params;
+    use crate::network::sim::{Config, Network};
+
+    #[test]
+    fn nonce_ranges_cover_nonce_space() {
+        for threads in 1..8 {
+            let ranges: Vec<_> = (0..threads).map(|i| nonce_range(i, threads)).collect();
+            assert_eq!(ranges[0].start, 0);
+            assert_eq!(ranges[threads - 1].end, u64::from(u32::MAX) + 1);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+            }
+        }
+    }
+
+    #[test]
+    fn run_limits() {
+        let begin = Instant::now();
+        assert!(!RunLimits::default().reached(begin, u64::MAX));
+        let limits = RunLimits { duration: None, count: Some(3) };
+        assert!(!limits.reached(begin, 2));
+        assert!(limits.reached(begin, 3));
+        let limits = RunLimits { duration: Some(Duration::from_secs(60)), count: None };
+        assert!(!limits.reached(begin, 100));
+        assert!(limits.reached(begin - Duration::from_secs(60), 0));
+    }
+
+    #[test]
+    fn search_finds_nonce_or_aborts() {
+        let mut difficulty = [255; 32];
+        difficulty[0] = 15;
+        let header = Header{parent: H256::default(), nonce: 0, difficulty: difficulty.into(), timestamp: 0, merkle_root: H256::default()};
+        let hash_rate = HashRate::default();
+        let (sender, receiver) = bounded(1);
+        search_nonces(header.clone(), nonce_range(1, 2), &AtomicBool::new(false), &hash_rate, &sender);
+        let found = receiver.try_recv().unwrap();
+        assert!(found.hash() <= found.difficulty);
+        assert!(u64::from(found.nonce) >= nonce_range(1, 2).start);
+        assert!(hash_rate.hashes.load(Ordering::Relaxed) > 0);
+
+        // nothing hashes under a zero difficulty, so only the abort flag ends the search
+        let header = Header{difficulty: H256::default(), ..header};
+        search_nonces(header, nonce_range(0, 1), &AtomicBool::new(true), &hash_rate, &sender);
+        assert!(receiver.try_recv().is_err());
+    }
 
-        let mut loop_duration = SystemTime::now().duration_since(loop_begin).unwrap().as_secs();
-        while loop_duration < 90 {
-            loop_duration = SystemTime::now().duration_since(loop_begin).unwrap().as_secs();
-            continue;
+    #[test]
+    fn submit_rejects_unknown_parent() {
+        let network = Network::new(1, Params::default(), Config::default());
+        let assembler = network.nodes[0].miner.assembler();
+        let mut block = assembler.template().block(0, 0);
+        let parent = generate_random_hash();
+        block.header.parent = parent;
+        assert_eq!(assembler.submit(&block), Err(SubmitError::Invalid(BlockValidationError::UnknownParent(parent))));
+        // the node is still usable
+        assert_eq!(assembler.template().height, 1);
+    }
+
+    #[test]
+    fn stopped_miner_refuses_signals() {
+        let network = Network::new(1, Params::default(), Config::default());
+        let miner = &network.nodes[0].miner;
+        assert!(miner.pause());
+        miner.stop();
+        // the miner thread drops its end of the channel once it has shut down
+        let begin = Instant::now();
+        while miner.pause() {
+            assert!(begin.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
         }
-        let blockchain = self.blockchain.lock().unwrap();
-        info!("BlockChain Length is {}", blockchain.total_size);
-        info!("BlockChain height is {}", blockchain.tip_height);
-        info!("BlockChain tip hash is {}", blockchain.tip_hash);
+        assert!(!miner.start(0));
     }
 }
@@ -0,0 +1,330 @@
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Most addresses remembered, the stalest untried ones are forgotten first beyond this
+const MAX_KNOWN: usize = 1000;
+/// How long to wait before dialing an address again after an attempt
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Wait before the first reconnection to a persistent peer, doubled on each failed attempt
+const RECONNECT_MIN: Duration = Duration::from_secs(5);
+/// Longest wait between reconnections to a persistent peer
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+
+/// What we know about a peer address
+#[derive(Debug, Clone)]
+pub struct AddrInfo {
+    /// When the address was last announced to us or connected to
+    pub last_seen: Instant,
+    /// When we last tried to connect to it
+    pub last_tried: Option<Instant>,
+    /// When we last completed a handshake with it
+    pub last_success: Option<Instant>,
+}
+
+/// Address manager, which records the peer addresses learned from gossip and connections and
+/// picks the ones to dial.
+#[derive(Default)]
+pub struct AddrManager {
+    known: HashMap<SocketAddr, AddrInfo>,
+    /// Banned hosts and when their ban ends
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl AddrManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrInfo> {
+        self.known.get(addr)
+    }
+
+    /// Record an address a peer told us about, returns false if it was ignored
+    pub fn add(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if addr.ip().is_unspecified() || addr.port() == 0 || self.is_banned(&addr.ip(), now) {
+            return false;
+        }
+        if let Some(info) = self.known.get_mut(&addr) {
+            info.last_seen = now;
+            return true;
+        }
+        if self.known.len() >= MAX_KNOWN && !self.evict() {
+            return false;
+        }
+        self.known.insert(addr, AddrInfo { last_seen: now, last_tried: None, last_success: None });
+        true
+    }
+
+    /// Forget the least recently seen address we never connected to, returns false if every
+    /// address has been connected to
+    fn evict(&mut self) -> bool {
+        let stalest = self
+            .known
+            .iter()
+            .filter(|(_, info)| info.last_success.is_none())
+            .min_by_key(|(_, info)| info.last_seen)
+            .map(|(addr, _)| *addr);
+        match stalest {
+            Some(addr) => {
+                self.known.remove(&addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.known.remove(addr);
+    }
+
+    /// Record a connection attempt to the address
+    pub fn mark_tried(&mut self, addr: SocketAddr, now: Instant) {
+        if self.add(addr, now) {
+            self.known.get_mut(&addr).unwrap().last_tried = Some(now);
+        }
+    }
+
+    /// Record a completed handshake with the address
+    pub fn mark_good(&mut self, addr: SocketAddr, now: Instant) {
+        if self.add(addr, now) {
+            self.known.get_mut(&addr).unwrap().last_success = Some(now);
+        }
+    }
+
+    /// Ban a host until the given time, forgetting its addresses
+    pub fn ban(&mut self, ip: IpAddr, until: Instant) {
+        self.known.retain(|addr, _| addr.ip() != ip);
+        self.banned.insert(ip, until);
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.banned.remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: Instant) -> bool {
+        match self.banned.get(ip) {
+            Some(until) => now < *until,
+            None => false,
+        }
+    }
+
+    /// Banned hosts with the time left on their ban
+    pub fn banned(&self, now: Instant) -> Vec<(IpAddr, Duration)> {
+        self.banned
+            .iter()
+            .filter(|(_, until)| now < **until)
+            .map(|(ip, until)| (*ip, *until - now))
+            .collect()
+    }
+
+    /// Pick an address to dial that is not connected and has not been tried recently, preferring
+    /// addresses we have connected to before
+    pub fn select(&self, connected: &HashSet<SocketAddr>, now: Instant) -> Option<SocketAddr> {
+        let candidates: Vec<(&SocketAddr, &AddrInfo)> = self
+            .known
+            .iter()
+            .filter(|(addr, info)| {
+                !connected.contains(*addr)
+                    && !self.is_banned(&addr.ip(), now)
+                    && info.last_tried.is_none_or(|t| now.duration_since(t) >= RETRY_INTERVAL)
+            })
+            .collect();
+        let good: Vec<SocketAddr> = candidates
+            .iter()
+            .filter(|(_, info)| info.last_success.is_some())
+            .map(|(addr, _)| **addr)
+            .collect();
+        let mut rng = rand::thread_rng();
+        if let Some(addr) = good.choose(&mut rng) {
+            return Some(*addr);
+        }
+        candidates.choose(&mut rng).map(|(addr, _)| **addr)
+    }
+
+    /// Up to `max` random known addresses to share with a peer
+    pub fn sample(&self, max: usize, now: Instant) -> Vec<SocketAddr> {
+        let addrs: Vec<SocketAddr> = self
+            .known
+            .keys()
+            .filter(|addr| !self.is_banned(&addr.ip(), now))
+            .cloned()
+            .collect();
+        addrs.choose_multiple(&mut rand::thread_rng(), max).cloned().collect()
+    }
+}
+
+struct Backoff {
+    /// Failed connection attempts since the last handshake
+    failures: u32,
+    next_try: Instant,
+}
+
+/// Peers given on the command line, which we keep reconnecting to with exponential backoff
+#[derive(Default)]
+pub struct PersistentPeers {
+    peers: HashMap<SocketAddr, Backoff>,
+}
+
+impl PersistentPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    /// Add a peer to connect to right away
+    pub fn add(&mut self, addr: SocketAddr, now: Instant) {
+        self.peers.insert(addr, Backoff { failures: 0, next_try: now });
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> bool {
+        self.peers.remove(addr).is_some()
+    }
+
+    /// The peers that are not connected and due for a connection attempt
+    pub fn due(&self, connected: &HashSet<SocketAddr>, now: Instant) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(addr, backoff)| !connected.contains(*addr) && backoff.next_try <= now)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Record a failed connection attempt or a dropped connection, and put off the next attempt
+    pub fn failed(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(backoff) = self.peers.get_mut(addr) {
+            let wait = RECONNECT_MIN
+                .checked_mul(1 << backoff.failures.min(16))
+                .map_or(RECONNECT_MAX, |wait| wait.min(RECONNECT_MAX));
+            backoff.failures += 1;
+            backoff.next_try = now + wait;
+        }
+    }
+
+    /// Record a completed handshake, so that a later drop is retried quickly
+    pub fn connected(&mut self, addr: &SocketAddr) {
+        if let Some(backoff) = self.peers.get_mut(addr) {
+            backoff.failures = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn select_skips_connected_and_recently_tried() {
+        let now = Instant::now();
+        let mut addrman = AddrManager::new();
+        assert!(addrman.add(addr(6001), now));
+        assert!(!addrman.add(SocketAddr::from(([0, 0, 0, 0], 6002)), now));
+        assert!(!addrman.add(addr(0), now));
+        assert_eq!(addrman.len(), 1);
+
+        let mut connected = HashSet::new();
+        assert_eq!(addrman.select(&connected, now), Some(addr(6001)));
+        connected.insert(addr(6001));
+        assert_eq!(addrman.select(&connected, now), None);
+
+        addrman.mark_tried(addr(6003), now);
+        assert_eq!(addrman.select(&connected, now), None);
+        assert_eq!(addrman.select(&connected, now + RETRY_INTERVAL), Some(addr(6003)));
+    }
+
+    #[test]
+    fn select_prefers_good_addresses() {
+        let now = Instant::now();
+        let mut addrman = AddrManager::new();
+        for port in 6001..6010 {
+            addrman.add(addr(port), now);
+        }
+        addrman.mark_tried(addr(6005), now);
+        addrman.mark_good(addr(6005), now);
+        let later = now + RETRY_INTERVAL;
+        for _ in 0..10 {
+            assert_eq!(addrman.select(&HashSet::new(), later), Some(addr(6005)));
+        }
+        assert_eq!(addrman.sample(3, now).len(), 3);
+        assert_eq!(addrman.sample(100, now).len(), 9);
+    }
+
+    #[test]
+    fn bans_expire() {
+        let now = Instant::now();
+        let mut addrman = AddrManager::new();
+        addrman.add(addr(6001), now);
+        addrman.add(SocketAddr::from(([127, 0, 0, 2], 6001)), now);
+        let ip = addr(6001).ip();
+        addrman.ban(ip, now + Duration::from_secs(10));
+        assert_eq!(addrman.len(), 1);
+        assert!(addrman.is_banned(&ip, now));
+        assert!(!addrman.add(addr(6001), now));
+        assert_eq!(addrman.banned(now), vec![(ip, Duration::from_secs(10))]);
+
+        let later = now + Duration::from_secs(10);
+        assert!(!addrman.is_banned(&ip, later));
+        assert!(addrman.banned(later).is_empty());
+        assert!(addrman.add(addr(6001), later));
+    }
+
+    #[test]
+    fn eviction_keeps_good_addresses() {
+        let now = Instant::now();
+        let mut addrman = AddrManager::new();
+        addrman.mark_good(addr(1), now);
+        for port in 2..=MAX_KNOWN as u16 {
+            addrman.add(addr(port), now + Duration::from_secs(port as u64));
+        }
+        assert_eq!(addrman.len(), MAX_KNOWN);
+        assert!(addrman.add(addr(60000), now + Duration::from_secs(60000)));
+        assert_eq!(addrman.len(), MAX_KNOWN);
+        assert!(addrman.get(&addr(1)).is_some());
+        assert!(addrman.get(&addr(2)).is_none());
+    }
+
+    #[test]
+    fn persistent_peers_back_off() {
+        let now = Instant::now();
+        let mut persistent = PersistentPeers::new();
+        persistent.add(addr(6001), now);
+        let mut connected = HashSet::new();
+        assert_eq!(persistent.due(&connected, now), vec![addr(6001)]);
+
+        persistent.failed(&addr(6001), now);
+        assert!(persistent.due(&connected, now + RECONNECT_MIN / 2).is_empty());
+        assert_eq!(persistent.due(&connected, now + RECONNECT_MIN).len(), 1);
+        persistent.failed(&addr(6001), now);
+        assert!(persistent.due(&connected, now + RECONNECT_MIN).is_empty());
+        assert_eq!(persistent.due(&connected, now + RECONNECT_MIN * 2).len(), 1);
+        for _ in 0..40 {
+            persistent.failed(&addr(6001), now);
+        }
+        assert_eq!(persistent.due(&connected, now + RECONNECT_MAX).len(), 1);
+
+        // a handshake resets the backoff, and connected peers are never due
+        persistent.connected(&addr(6001));
+        persistent.failed(&addr(6001), now);
+        assert_eq!(persistent.due(&connected, now + RECONNECT_MIN).len(), 1);
+        connected.insert(addr(6001));
+        assert!(persistent.due(&connected, now + RECONNECT_MAX).is_empty());
+        assert!(persistent.remove(&addr(6001)));
+        assert!(!persistent.contains(&addr(6001)));
+    }
+}
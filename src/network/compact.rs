@@ -0,0 +1,208 @@
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{Hashable, H256};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::{Mempool, SignedTransaction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Most transactions a compact block may stand for
+const MAX_TRANSACTIONS: usize = 100_000;
+
+/// A block relayed as its header and short IDs of its transactions, which the receiver looks up in
+/// its mempool. The coinbase, which no mempool has, is sent in full.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    /// Salts the short IDs, so that no one can craft transactions whose IDs collide in advance
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    /// Transactions sent in full, with their index in the block, in increasing index order
+    pub prefilled: Vec<(u32, SignedTransaction)>,
+}
+
+/// Short ID of a transaction in a compact block: the first 8 bytes of the SHA256 digest of the
+/// block hash, the nonce and the transaction hash
+pub fn short_id(block_hash: &H256, nonce: u64, tx_hash: &H256) -> u64 {
+    let mut data = Vec::with_capacity(72);
+    data.extend_from_slice(block_hash.as_ref());
+    data.extend_from_slice(&nonce.to_le_bytes());
+    data.extend_from_slice(tx_hash.as_ref());
+    let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+    u64::from_le_bytes(digest.as_ref()[..8].try_into().unwrap())
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let hash = block.hash();
+        let mut short_ids = Vec::new();
+        let mut prefilled = Vec::new();
+        for (i, tx) in block.content.content.iter().enumerate() {
+            if tx.transaction.is_coinbase() {
+                prefilled.push((i as u32, tx.clone()));
+            } else {
+                short_ids.push(short_id(&hash, nonce, &tx.hash()));
+            }
+        }
+        CompactBlock { header: block.header.clone(), nonce, short_ids, prefilled }
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+/// A block being rebuilt from a compact block, with the transactions not found in the mempool
+/// still missing
+pub struct PartialBlock {
+    header: Header,
+    slots: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Fill in a compact block's transactions from the mempool. Returns None if the compact block
+    /// is malformed. A short ID matching several mempool transactions is left missing.
+    pub fn reconstruct(compact: &CompactBlock, mempool: &Mempool) -> Option<Self> {
+        let total = compact.short_ids.len() + compact.prefilled.len();
+        if total == 0 || total > MAX_TRANSACTIONS {
+            return None;
+        }
+        let mut slots: Vec<Option<SignedTransaction>> = vec![None; total];
+        let mut last = None;
+        for (index, tx) in compact.prefilled.iter() {
+            let index = *index as usize;
+            if index >= total || last.is_some_and(|last| index <= last) {
+                return None;
+            }
+            slots[index] = Some(tx.clone());
+            last = Some(index);
+        }
+
+        let hash = compact.hash();
+        let mut by_short_id: HashMap<u64, Option<&SignedTransaction>> = HashMap::new();
+        for entry in mempool.data.values() {
+            let id = short_id(&hash, compact.nonce, &entry.transaction.hash());
+            by_short_id
+                .entry(id)
+                .and_modify(|tx| *tx = None)
+                .or_insert(Some(&entry.transaction));
+        }
+        let empty = slots.iter_mut().filter(|slot| slot.is_none());
+        for (slot, id) in empty.zip(compact.short_ids.iter()) {
+            if let Some(Some(tx)) = by_short_id.get(id) {
+                *slot = Some((*tx).clone());
+            }
+        }
+        Some(PartialBlock { header: compact.header.clone(), slots })
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+
+    /// Indexes of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Fill in the missing transactions, in index order. Returns false if the number of
+    /// transactions does not match.
+    pub fn fill(&mut self, txs: Vec<SignedTransaction>) -> bool {
+        if txs.len() != self.missing().len() {
+            return false;
+        }
+        let empty = self.slots.iter_mut().filter(|slot| slot.is_none());
+        for (slot, tx) in empty.zip(txs) {
+            *slot = Some(tx);
+        }
+        true
+    }
+
+    /// Get the block once no transaction is missing. Returns None if a transaction is still
+    /// missing, or if the transactions don't match the merkle root, as when a short ID matched
+    /// the wrong mempool transaction.
+    pub fn into_block(self) -> Option<Block> {
+        let content: Option<Vec<SignedTransaction>> = self.slots.into_iter().collect();
+        let content = content?;
+        if MerkleTree::new(&content).root() != self.header.merkle_root {
+            return None;
+        }
+        Some(Block { header: self.header, content: Content { content } })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::generate_random_signedtransaction;
+
+    /// A transaction with its own hash, spending a made-up output
+    fn transaction(i: u8) -> SignedTransaction {
+        let mut tx = generate_random_signedtransaction();
+        tx.transaction.in_put[0].tx_hash = H256::from([i; 32]);
+        tx
+    }
+
+    fn block(txs: Vec<SignedTransaction>) -> Block {
+        let mut block = crate::block::test::generate_random_block(&H256::default());
+        block.header.merkle_root = MerkleTree::new(&txs).root();
+        block.content.content = txs;
+        block
+    }
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let coinbase = SignedTransaction::coinbase(1, Default::default(), 50);
+        let txs: Vec<SignedTransaction> = (1..5).map(transaction).collect();
+        let mut all = vec![coinbase];
+        all.extend(txs.iter().cloned());
+        let block = block(all);
+        let compact = CompactBlock::new(&block, 7);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.short_ids.len(), 4);
+
+        // the mempool has transactions 0 and 2, along with an unrelated one
+        let mut mempool = Mempool::new();
+        mempool.insert(&txs[0], 0);
+        mempool.insert(&txs[2], 0);
+        mempool.insert(&transaction(9), 0);
+        let mut partial = PartialBlock::reconstruct(&compact, &mempool).unwrap();
+        assert_eq!(partial.hash(), block.hash());
+        assert_eq!(partial.missing(), vec![2, 4]);
+        assert!(!partial.fill(vec![txs[1].clone()]));
+        assert!(partial.fill(vec![txs[1].clone(), txs[3].clone()]));
+        assert!(partial.missing().is_empty());
+        let rebuilt = partial.into_block().unwrap();
+        assert_eq!(rebuilt.hash(), block.hash());
+        assert_eq!(rebuilt.content.content.len(), 5);
+
+        // transactions in the wrong slots don't match the merkle root
+        let mut partial = PartialBlock::reconstruct(&compact, &Mempool::new()).unwrap();
+        assert!(partial.fill(vec![txs[1].clone(), txs[0].clone(), txs[2].clone(), txs[3].clone()]));
+        assert!(partial.into_block().is_none());
+    }
+
+    #[test]
+    fn malformed_compact_blocks() {
+        let txs: Vec<SignedTransaction> = (1..3).map(transaction).collect();
+        let compact = CompactBlock::new(&block(txs.clone()), 1);
+        let mempool = Mempool::new();
+        assert!(PartialBlock::reconstruct(&compact, &mempool).is_some());
+
+        let mut out_of_range = compact.clone();
+        out_of_range.prefilled.push((5, txs[0].clone()));
+        assert!(PartialBlock::reconstruct(&out_of_range, &mempool).is_none());
+        let mut unordered = compact.clone();
+        unordered.short_ids.truncate(0);
+        unordered.prefilled = vec![(1, txs[1].clone()), (0, txs[0].clone())];
+        assert!(PartialBlock::reconstruct(&unordered, &mempool).is_none());
+        let mut empty = compact;
+        empty.short_ids.clear();
+        assert!(PartialBlock::reconstruct(&empty, &mempool).is_none());
+    }
+}
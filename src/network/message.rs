@@ -1,7 +1,39 @@
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 use crate::crypto::hash::H256;
-use crate::block::Block;
+use crate::block::{Block, Header};
 use crate::transaction::SignedTransaction;
+use super::compact::CompactBlock;
+
+/// Version of the peer protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this node can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Identifies this network, so nodes of other networks are told apart before any block is exchanged
+pub const NETWORK_MAGIC: u32 = 0x598f_b7c0;
+/// Service bit of a node that keeps the full chain and serves blocks and transactions
+pub const SERVICE_FULL_NODE: u64 = 1;
+/// Most addresses carried by one `Addr` message
+pub const MAX_ADDR: usize = 1000;
+/// Most headers carried by one `Headers` message, a full message means more may follow
+pub const MAX_HEADERS: usize = 2000;
+/// Most hashes carried by one inventory message
+pub const MAX_INV: usize = 50_000;
+/// Most blocks carried by one `Blocks` message
+pub const MAX_BLOCKS: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub version: u32,
+    pub magic: u32,
+    pub genesis: H256,
+    pub best_height: u32,
+    pub services: u64,
+    /// Port the node accepts connections on, which differs from the port of its outgoing connections
+    pub listen_port: u16,
+    /// Random per-process value, a node receiving its own nonce has connected to itself
+    pub nonce: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -13,4 +45,63 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransaction(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Version(Version),
+    Verack,
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    /// Ask for the headers following the first hash of a block locator known to the peer
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// A new block, sent as its header and the short IDs of its transactions
+    CompactBlock(CompactBlock),
+    /// Ask for the transactions at the given indexes of a block received as a compact block
+    GetBlockTransactions(H256, Vec<u32>),
+    BlockTransactions(H256, Vec<SignedTransaction>),
+}
+
+impl Message {
+    /// Identifies the type of the message in the frame header, so that its size can be checked
+    /// before the message is read
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::Ping(_) => 0,
+            Message::Pong(_) => 1,
+            Message::NewBlockHashes(_) => 2,
+            Message::GetBlocks(_) => 3,
+            Message::Blocks(_) => 4,
+            Message::NewTransactionHashes(_) => 5,
+            Message::GetTransaction(_) => 6,
+            Message::Transactions(_) => 7,
+            Message::Version(_) => 8,
+            Message::Verack => 9,
+            Message::GetAddr => 10,
+            Message::Addr(_) => 11,
+            Message::GetHeaders(_) => 12,
+            Message::Headers(_) => 13,
+            Message::CompactBlock(_) => 14,
+            Message::GetBlockTransactions(_, _) => 15,
+            Message::BlockTransactions(_, _) => 16,
+        }
+    }
+}
+
+/// Largest encoded size accepted for a message of the given kind, or None for an unknown kind
+pub fn max_size(kind: u8) -> Option<usize> {
+    // the variant tag and the length of the list, then the hashes
+    let inventory = 4 + 8 + 32 * MAX_INV;
+    match kind {
+        0 | 1 => Some(1024),
+        2 | 3 | 5 | 6 => Some(inventory),
+        4 => Some(32_000_000),
+        7 => Some(16_000_000),
+        8 => Some(256),
+        9 | 10 => Some(16),
+        11 => Some(64 * 1024),
+        12 => Some(64 * 1024),
+        13 => Some(512 * 1024),
+        14 => Some(4_000_000),
+        15 => Some(1_000_000),
+        16 => Some(16_000_000),
+        _ => None,
+    }
 }
@@ -1,4 +1,11 @@
+pub mod addrman;
+pub mod compact;
 pub mod message;
+pub mod orphan;
 pub mod peer;
 pub mod server;
+#[cfg(any(test, feature = "test-utilities"))]
+pub mod sim;
+pub mod sync;
+pub mod transport;
 pub mod worker;
@@ -0,0 +1,172 @@
+use crate::block::Block;
+use crate::crypto::hash::{Hashable, H256};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Most orphan blocks kept, the oldest are evicted first beyond this
+const MAX_ORPHANS: usize = 256;
+/// How long an orphan waits for its parent before it is evicted
+const ORPHAN_EXPIRY: Duration = Duration::from_secs(600);
+
+struct OrphanEntry {
+    block: Block,
+    time: Instant,
+}
+
+/// Blocks whose parent we do not have yet, indexed by parent hash so that they can be connected
+/// as soon as the parent arrives
+#[derive(Default)]
+pub struct OrphanPool {
+    orphans: HashMap<H256, OrphanEntry>,
+    by_parent: HashMap<H256, HashSet<H256>>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Add an orphan block, evicting the oldest orphan if the pool is full. Returns false if the
+    /// block was already in the pool.
+    pub fn insert(&mut self, block: &Block, now: Instant) -> bool {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return false;
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            let oldest = *self.orphans.iter().min_by_key(|(_, entry)| entry.time).unwrap().0;
+            self.remove(&oldest);
+        }
+        self.by_parent.entry(block.header.parent).or_default().insert(hash);
+        self.orphans.insert(hash, OrphanEntry { block: block.clone(), time: now });
+        true
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let entry = self.orphans.remove(hash)?;
+        let parent = entry.block.header.parent;
+        let siblings = self.by_parent.get_mut(&parent).unwrap();
+        siblings.remove(hash);
+        if siblings.is_empty() {
+            self.by_parent.remove(&parent);
+        }
+        Some(entry.block)
+    }
+
+    /// Take the orphans whose parent is the given block out of the pool
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let children = match self.by_parent.remove(parent) {
+            Some(children) => children,
+            None => return vec![],
+        };
+        children.iter().map(|hash| self.orphans.remove(hash).unwrap().block).collect()
+    }
+
+    /// Drop the orphans descending from a block, when it turned out to be invalid
+    pub fn remove_descendants(&mut self, hash: &H256) -> usize {
+        let mut count = 0;
+        let mut queue = vec![*hash];
+        while let Some(parent) = queue.pop() {
+            for child in self.take_children(&parent) {
+                queue.push(child.hash());
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Get the block an orphan is ultimately waiting for: the missing parent of its oldest
+    /// ancestor in the pool
+    pub fn missing_ancestor(&self, hash: &H256) -> H256 {
+        let mut cursor = *hash;
+        while let Some(entry) = self.orphans.get(&cursor) {
+            cursor = entry.block.header.parent;
+        }
+        cursor
+    }
+
+    /// Evict the orphans that waited too long for their parent, returns how many were evicted
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, entry)| now.duration_since(entry.time) >= ORPHAN_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.remove(hash);
+        }
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    #[test]
+    fn connect_children() {
+        let now = Instant::now();
+        let mut pool = OrphanPool::new();
+        let missing = H256::from([1; 32]);
+        let a = generate_random_block(&missing);
+        let b1 = generate_random_block(&a.hash());
+        let b2 = generate_random_block(&a.hash());
+        let c = generate_random_block(&b1.hash());
+        for block in [&c, &b1, &b2, &a].iter() {
+            assert!(pool.insert(block, now));
+        }
+        assert!(!pool.insert(&a, now));
+        assert_eq!(pool.missing_ancestor(&c.hash()), missing);
+        assert_eq!(pool.missing_ancestor(&missing), missing);
+
+        let children = pool.take_children(&missing);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].hash(), a.hash());
+        let mut children: Vec<H256> = pool.take_children(&a.hash()).iter().map(|b| b.hash()).collect();
+        children.sort();
+        let mut expected = vec![b1.hash(), b2.hash()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert!(pool.take_children(&a.hash()).is_empty());
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.remove_descendants(&b1.hash()), 1);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn limits_and_expiry() {
+        let now = Instant::now();
+        let mut pool = OrphanPool::new();
+        let first = generate_random_block(&H256::from([1; 32]));
+        pool.insert(&first, now);
+        for i in 1..MAX_ORPHANS {
+            let block = generate_random_block(&H256::from([1; 32]));
+            pool.insert(&block, now + Duration::from_secs(i as u64));
+        }
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        // a full pool makes room by evicting the oldest orphan
+        let last = generate_random_block(&H256::from([2; 32]));
+        pool.insert(&last, now + ORPHAN_EXPIRY);
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&first.hash()));
+
+        assert_eq!(pool.expire(now + ORPHAN_EXPIRY + Duration::from_secs(1)), 1);
+        assert_eq!(pool.expire(now + ORPHAN_EXPIRY * 2), MAX_ORPHANS - 1);
+        assert!(pool.is_empty());
+        assert!(pool.take_children(&H256::from([1; 32])).is_empty());
+    }
+}
@@ -1,35 +1,86 @@
 use super::message;
+use super::transport::Stream;
+use crate::crypto::hash::H256;
+use crate::validation::{BlockValidationError, TransactionError};
 use log::{trace, warn};
-use mio;
 use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use std::time::Instant;
+
+/// Size of the frame header: the message kind, the payload length and the payload checksum
+pub const FRAME_HEADER_SIZE: usize = 9;
+
+/// First four bytes of the SHA256 digest of a payload
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    digest.as_ref()[..4].try_into().unwrap()
+}
+
+/// Encode a message into a frame: a one-byte message kind, the big-endian payload length, the
+/// payload checksum, then the payload
+pub fn encode(msg: &message::Message) -> Vec<u8> {
+    let payload = bincode::serialize(msg).unwrap();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.push(msg.kind());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn invalid_data(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
 
 enum DecodeState {
-    Length,
-    Payload,
+    Header,
+    Payload { kind: u8, checksum: [u8; 4] },
 }
 
 pub enum ReadResult {
     Continue,
-    Message(Vec<u8>),
+    /// The message kind from the frame header, and the payload
+    Message(u8, Vec<u8>),
     EOF,
 }
 
-pub struct ReadContext {
-    reader: std::io::BufReader<mio::net::TcpStream>,
+/// Deserialize a payload, which must hold a message of the kind its frame header announced, since
+/// the frame size was only checked against that kind
+pub fn parse_message(kind: u8, payload: &[u8]) -> Result<message::Message, String> {
+    let msg: message::Message = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+    if msg.kind() != kind {
+        return Err(format!("message of kind {} framed as kind {}", msg.kind(), kind));
+    }
+    Ok(msg)
+}
+
+/// Frame decoder, which reads the frame header first and checks the length against the largest
+/// size of the message kind before reading the payload
+pub struct Decoder {
     buffer: Vec<u8>,
     msg_length: usize,
     read_length: usize,
     state: DecodeState,
 }
 
-impl ReadContext {
-    pub fn read(&mut self) -> std::io::Result<ReadResult> {
-        let bytes_read = self
-            .reader
-            .read(&mut self.buffer[self.read_length..self.msg_length]);
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            buffer: vec![0; FRAME_HEADER_SIZE],
+            msg_length: FRAME_HEADER_SIZE,
+            read_length: 0,
+            state: DecodeState::Header,
+        }
+    }
+}
+
+impl Decoder {
+    /// Read from `reader` once. Framing errors are returned as `InvalidData` errors, after which
+    /// the connection should be dropped.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> std::io::Result<ReadResult> {
+        let bytes_read = reader.read(&mut self.buffer[self.read_length..self.msg_length]);
         match bytes_read {
             Ok(0) => {
                 trace!("Detected socket EOF");
@@ -39,37 +90,80 @@ impl ReadContext {
                 trace!("Read {} bytes from socket", size);
                 // we got some data, move the cursor
                 self.read_length += size;
-                if self.read_length == self.msg_length {
-                    // buffer filled, process the buffer
-                    match self.state {
-                        DecodeState::Length => {
-                            let message_length =
-                                u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
-                            self.state = DecodeState::Payload;
-                            self.read_length = 0;
-                            self.msg_length = message_length as usize;
-                            if self.buffer.len() < self.msg_length {
-                                self.buffer.resize(self.msg_length, 0);
-                            }
-                            trace!("Received message length={}", message_length);
-                            Ok(ReadResult::Continue)
+                if self.read_length < self.msg_length {
+                    return Ok(ReadResult::Continue);
+                }
+                // buffer filled, process the buffer
+                match self.state {
+                    DecodeState::Header => {
+                        let kind = self.buffer[0];
+                        let message_length =
+                            u32::from_be_bytes(self.buffer[1..5].try_into().unwrap()) as usize;
+                        let max_size = match message::max_size(kind) {
+                            Some(max_size) => max_size,
+                            None => return Err(invalid_data(format!("unknown message kind {}", kind))),
+                        };
+                        if message_length > max_size {
+                            return Err(invalid_data(format!(
+                                "message of kind {} is {} bytes, more than {}",
+                                kind, message_length, max_size
+                            )));
+                        }
+                        self.state = DecodeState::Payload {
+                            kind,
+                            checksum: self.buffer[5..9].try_into().unwrap(),
+                        };
+                        self.read_length = 0;
+                        self.msg_length = message_length;
+                        if self.buffer.len() < self.msg_length {
+                            self.buffer.resize(self.msg_length, 0);
                         }
-                        DecodeState::Payload => {
-                            let new_payload: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
-                            self.state = DecodeState::Length;
-                            self.read_length = 0;
-                            self.msg_length = std::mem::size_of::<u32>();
-                            trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
+                        trace!("Received message length={}", message_length);
+                        if message_length == 0 {
+                            // there is nothing to read for an empty payload
+                            return self.finish_payload();
                         }
+                        Ok(ReadResult::Continue)
                     }
-                } else {
-                    Ok(ReadResult::Continue)
+                    DecodeState::Payload { .. } => self.finish_payload(),
                 }
             }
             Err(e) => Err(e),
         }
     }
+
+    fn finish_payload(&mut self) -> std::io::Result<ReadResult> {
+        let payload = &self.buffer[0..self.msg_length];
+        let kind = match self.state {
+            DecodeState::Payload { kind, checksum: expected } => {
+                if checksum(payload) != expected {
+                    return Err(invalid_data("message checksum mismatch".to_string()));
+                }
+                kind
+            }
+            DecodeState::Header => unreachable!(),
+        };
+        let new_payload = payload.to_vec();
+        self.state = DecodeState::Header;
+        self.read_length = 0;
+        self.msg_length = FRAME_HEADER_SIZE;
+        // don't hold on to the memory of a large message
+        self.buffer.truncate(FRAME_HEADER_SIZE);
+        self.buffer.shrink_to_fit();
+        trace!("Received full message");
+        Ok(ReadResult::Message(kind, new_payload))
+    }
+}
+
+pub struct ReadContext {
+    reader: std::io::BufReader<Box<dyn Stream>>,
+    decoder: Decoder,
+}
+
+impl ReadContext {
+    pub fn read(&mut self) -> std::io::Result<ReadResult> {
+        self.decoder.read_from(&mut self.reader)
+    }
 }
 
 pub enum WriteResult {
@@ -78,85 +172,54 @@ pub enum WriteResult {
     ChanClosed,
 }
 
-enum WriteState {
-    Length,
-    Payload,
-}
-
 pub struct WriteContext {
-    writer: std::io::BufWriter<mio::net::TcpStream>,
+    writer: std::io::BufWriter<Box<dyn Stream>>,
     pub queue: channel::Receiver<Vec<u8>>,
-    len_buffer: [u8; std::mem::size_of::<u32>()],
     msg_buffer: Vec<u8>,
     msg_length: usize,
     written_length: usize,
-    state: WriteState,
+    /// Bytes written to the socket so far
+    pub bytes_sent: u64,
 }
 
 impl WriteContext {
     pub fn write(&mut self) -> std::io::Result<WriteResult> {
         loop {
-            match self.state {
-                WriteState::Length => {
-                    if self.written_length == std::mem::size_of::<u32>() {
-                        // if the length part has been fully sent
-                        self.written_length = 0;
-                        self.state = WriteState::Payload;
-                        continue;
-                    } else {
-                        // we are still sending the length part
-                        let written = self.writer.write(
-                            &self.len_buffer[self.written_length..std::mem::size_of::<u32>()],
-                        )?;
-                        if written == 0 {
-                            return Ok(WriteResult::EOF);
-                        }
-                        self.written_length += written;
-                        continue;
-                    }
-                }
-                WriteState::Payload => {
-                    if self.written_length == self.msg_length {
-                        // if the previous message has been fully written, try to get the next message
-                        // first flush the writer
-                        self.writer.flush()?;
-                        let msg = match self.queue.try_recv() {
-                            Ok(msg) => msg,
-                            Err(e) => match e {
-                                mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
-                                mpsc::TryRecvError::Disconnected => {
-                                    return Ok(WriteResult::ChanClosed);
-                                }
-                            },
-                        };
-
-                        // encode the message and the length
-                        self.msg_buffer = msg;
-                        self.msg_length = self.msg_buffer.len();
-                        self.len_buffer[..4]
-                            .copy_from_slice(&(self.msg_length as u32).to_be_bytes());
-                        self.written_length = 0;
-                        self.state = WriteState::Length;
-                        continue;
-                    } else {
-                        // we are still sending the payload
-                        let written = self
-                            .writer
-                            .write(&self.msg_buffer[self.written_length..self.msg_length])?;
-                        if written == 0 {
-                            return Ok(WriteResult::EOF);
+            if self.written_length == self.msg_length {
+                // if the previous frame has been fully written, try to get the next frame
+                // first flush the writer
+                self.writer.flush()?;
+                let msg = match self.queue.try_recv() {
+                    Ok(msg) => msg,
+                    Err(e) => match e {
+                        mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
+                        mpsc::TryRecvError::Disconnected => {
+                            return Ok(WriteResult::ChanClosed);
                         }
-                        self.written_length += written;
-                        continue;
-                    }
+                    },
+                };
+                // the frame is encoded by the handle
+                self.msg_buffer = msg;
+                self.msg_length = self.msg_buffer.len();
+                self.written_length = 0;
+            } else {
+                // we are still sending the frame
+                let written = self
+                    .writer
+                    .write(&self.msg_buffer[self.written_length..self.msg_length])?;
+                if written == 0 {
+                    return Ok(WriteResult::EOF);
                 }
+                self.written_length += written;
+                self.bytes_sent += written as u64;
             }
         }
     }
 }
 
 pub fn new(
-    stream: mio::net::TcpStream,
+    id: usize,
+    stream: Box<dyn Stream>,
     direction: Direction,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
@@ -165,23 +228,20 @@ pub fn new(
     let bufreader = std::io::BufReader::new(reader_stream);
     let read_ctx = ReadContext {
         reader: bufreader,
-        buffer: vec![0; std::mem::size_of::<u32>()],
-        msg_length: std::mem::size_of::<u32>(),
-        read_length: 0,
-        state: DecodeState::Length,
+        decoder: Decoder::default(),
     };
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
     let write_ctx = WriteContext {
         writer: bufwriter,
         queue: write_receiver,
-        len_buffer: [0; std::mem::size_of::<u32>()],
         msg_buffer: Vec::new(),
         msg_length: 0,
         written_length: 0,
-        state: WriteState::Payload,
+        bytes_sent: 0,
     };
     let handle = Handle {
+        id,
         write_queue: write_sender,
         addr,
     };
@@ -192,11 +252,16 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        handshake: Handshake::default(),
+        misbehavior: 0,
+        connected_at: Instant::now(),
+        last_seen: Instant::now(),
+        bytes_received: 0,
     };
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -204,25 +269,378 @@ pub enum Direction {
 
 pub struct Context {
     pub addr: std::net::SocketAddr,
-    pub stream: mio::net::TcpStream,
+    pub stream: Box<dyn Stream>,
     pub reader: ReadContext,
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    pub handshake: Handshake,
+    /// Accumulated misbehavior score, the peer is banned once it reaches `BAN_THRESHOLD`
+    pub misbehavior: u32,
+    pub connected_at: Instant,
+    /// When the peer last sent us a message
+    pub last_seen: Instant,
+    pub bytes_received: u64,
+}
+
+/// What the server knows about a connected peer
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: usize,
+    pub addr: std::net::SocketAddr,
+    pub direction: Direction,
+    /// Whether the version handshake is complete
+    pub ready: bool,
+    pub best_height: Option<u32>,
+    pub connected_at: Instant,
+    pub last_seen: Instant,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub misbehavior: u32,
+}
+
+impl Context {
+    /// The address the peer accepts connections on, known for incoming peers once they have
+    /// announced their version
+    pub fn listen_addr(&self) -> Option<std::net::SocketAddr> {
+        match self.direction {
+            Direction::Outgoing => Some(self.addr),
+            Direction::Incoming => self
+                .handshake
+                .version
+                .as_ref()
+                .map(|v| std::net::SocketAddr::new(self.addr.ip(), v.listen_port)),
+        }
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            id: self.handle.id,
+            addr: self.addr,
+            direction: self.direction,
+            ready: self.handshake.is_complete(),
+            best_height: self.handshake.version.as_ref().map(|v| v.best_height),
+            connected_at: self.connected_at,
+            last_seen: self.last_seen,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.writer.bytes_sent,
+            misbehavior: self.misbehavior,
+        }
+    }
+}
+
+/// Progress of the version handshake with a peer. Both sides send their `Version` upon
+/// connecting and acknowledge the other's with `Verack`.
+#[derive(Default)]
+pub struct Handshake {
+    /// The version the peer announced, once it has been accepted
+    pub version: Option<message::Version>,
+    /// Whether the peer has acknowledged our version
+    pub verack: bool,
+}
+
+impl Handshake {
+    pub fn is_complete(&self) -> bool {
+        self.version.is_some() && self.verack
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    WrongNetwork(u32),
+    UnsupportedVersion(u32),
+    WrongGenesis(H256),
+    SelfConnection,
+}
+
+/// Check the version announced by a peer against our own
+pub fn check_version(
+    local: &message::Version,
+    remote: &message::Version,
+) -> Result<(), HandshakeError> {
+    if remote.magic != local.magic {
+        return Err(HandshakeError::WrongNetwork(remote.magic));
+    }
+    if remote.version < message::MIN_PROTOCOL_VERSION {
+        return Err(HandshakeError::UnsupportedVersion(remote.version));
+    }
+    if remote.genesis != local.genesis {
+        return Err(HandshakeError::WrongGenesis(remote.genesis));
+    }
+    if remote.nonce == local.nonce {
+        return Err(HandshakeError::SelfConnection);
+    }
+    Ok(())
+}
+
+/// Misbehavior score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Ways a peer can break the protocol, each adding to its misbehavior score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A message that could not be decoded
+    MalformedMessage,
+    /// A block whose hash is above its difficulty target
+    BadProofOfWork,
+    /// A block that breaks a consensus rule
+    InvalidBlock,
+    /// A transaction that could never be valid
+    InvalidTransaction,
+    /// Blocks or transactions we did not ask for, or inventory with nothing in it
+    UnsolicitedData,
+}
+
+impl Misbehavior {
+    pub fn score(self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 50,
+            Misbehavior::BadProofOfWork => 100,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::UnsolicitedData => 20,
+        }
+    }
+
+    /// The misbehavior of sending a block that failed validation, if it is the sender's fault
+    pub fn from_block_error(e: &BlockValidationError) -> Option<Self> {
+        match e {
+            BlockValidationError::BadProofOfWork => Some(Misbehavior::BadProofOfWork),
+            // our clock may be behind, and we would ask for the block again later
+            BlockValidationError::TimestampTooLate { .. } => None,
+            BlockValidationError::UnknownParent(_) => None,
+            _ => Some(Misbehavior::InvalidBlock),
+        }
+    }
+
+    /// The misbehavior of relaying a transaction that failed validation, if it is the sender's
+    /// fault. Whether an output exists or is mature depends on our view of the chain and mempool.
+    pub fn from_transaction_error(e: &TransactionError) -> Option<Self> {
+        match e {
+            TransactionError::MissingInput(_) | TransactionError::ImmatureCoinbase(_) => None,
+            _ => Some(Misbehavior::InvalidTransaction),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
+    /// Index of the peer in the server's connection set
+    id: usize,
     addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
 }
 
 impl Handle {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+        let buffer = encode(&msg);
+        let max_size = message::max_size(msg.kind()).unwrap();
+        if buffer.len() - FRAME_HEADER_SIZE > max_size {
+            // the peer would drop the connection over it
+            warn!("Not sending {} byte message of kind {} to peer {}, it is too large", buffer.len(), msg.kind(), self.addr);
+            return;
+        }
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::network::message::{Version, NETWORK_MAGIC, PROTOCOL_VERSION, SERVICE_FULL_NODE};
+
+    fn version(nonce: u64) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            magic: NETWORK_MAGIC,
+            genesis: H256::default(),
+            best_height: 0,
+            services: SERVICE_FULL_NODE,
+            listen_port: 6000,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn version_check() {
+        let local = version(1);
+        let mut remote = version(2);
+        remote.best_height = 10;
+        remote.services = 0;
+        assert_eq!(check_version(&local, &remote), Ok(()));
+
+        let mut other = remote.clone();
+        other.magic = !NETWORK_MAGIC;
+        assert_eq!(check_version(&local, &other), Err(HandshakeError::WrongNetwork(!NETWORK_MAGIC)));
+
+        let mut other = remote.clone();
+        other.version = 0;
+        assert_eq!(check_version(&local, &other), Err(HandshakeError::UnsupportedVersion(0)));
+
+        let mut other = remote.clone();
+        other.genesis = [1u8; 32].into();
+        assert_eq!(check_version(&local, &other), Err(HandshakeError::WrongGenesis([1u8; 32].into())));
+
+        assert_eq!(check_version(&local, &version(1)), Err(HandshakeError::SelfConnection));
+    }
+
+    #[test]
+    fn misbehavior_of_validation_errors() {
+        let invalid_block = Misbehavior::from_block_error(&BlockValidationError::BadMerkleRoot);
+        assert_eq!(invalid_block, Some(Misbehavior::InvalidBlock));
+        assert!(invalid_block.unwrap().score() >= BAN_THRESHOLD);
+        let late = BlockValidationError::TimestampTooLate { now: 0 };
+        assert_eq!(Misbehavior::from_block_error(&late), None);
+
+        let bad_tx = Misbehavior::from_transaction_error(&TransactionError::BadSignature);
+        assert_eq!(bad_tx, Some(Misbehavior::InvalidTransaction));
+        assert!(bad_tx.unwrap().score() < BAN_THRESHOLD);
+        let missing = TransactionError::MissingInput(Default::default());
+        assert_eq!(Misbehavior::from_transaction_error(&missing), None);
+    }
+
+    /// Hands out at most `chunk` bytes per read, like a socket receiving a frame in pieces
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = std::cmp::min(std::cmp::min(self.chunk, buf.len()), self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    /// Decode until a message, the end of the data or an error
+    fn decode(data: &[u8], chunk: usize) -> std::io::Result<ReadResult> {
+        let mut decoder = Decoder::default();
+        let mut reader = Trickle { data, chunk };
+        loop {
+            match decoder.read_from(&mut reader)? {
+                ReadResult::Continue => continue,
+                result => return Ok(result),
+            }
+        }
+    }
+
+    fn is_invalid_data(result: std::io::Result<ReadResult>) -> bool {
+        match result {
+            Err(e) => e.kind() == std::io::ErrorKind::InvalidData,
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let msg = message::Message::Ping("hello".to_string());
+        let frame = encode(&msg);
+        assert_eq!(frame[0], msg.kind());
+        for chunk in [1, 4, frame.len()].iter() {
+            match decode(&frame, *chunk).unwrap() {
+                ReadResult::Message(kind, payload) => {
+                    assert_eq!(kind, msg.kind());
+                    assert_eq!(payload, bincode::serialize(&msg).unwrap());
+                }
+                _ => panic!("expected a message"),
+            }
+        }
+        // two frames back to back
+        let mut decoder = Decoder::default();
+        let mut data = encode(&message::Message::Verack);
+        data.extend(encode(&message::Message::GetAddr));
+        let mut reader = Trickle { data: &data, chunk: 3 };
+        let mut payloads = Vec::new();
+        while payloads.len() < 2 {
+            if let ReadResult::Message(_, payload) = decoder.read_from(&mut reader).unwrap() {
+                payloads.push(payload);
+            }
+        }
+        assert_eq!(payloads[1], bincode::serialize(&message::Message::GetAddr).unwrap());
+
+        // an empty payload is complete as soon as the header is read
+        let mut frame = vec![message::Message::Verack.kind(), 0, 0, 0, 0];
+        frame.extend_from_slice(&checksum(&[]));
+        assert!(matches!(decode(&frame, 100).unwrap(), ReadResult::Message(_, p) if p.is_empty()));
+    }
+
+    #[test]
+    fn codec_rejects_bad_frames() {
+        let frame = encode(&message::Message::Ping("hello".to_string()));
+        // truncated in the header or the payload
+        assert!(matches!(decode(&frame[..5], 2).unwrap(), ReadResult::EOF));
+        assert!(matches!(decode(&frame[..frame.len() - 1], 2).unwrap(), ReadResult::EOF));
+
+        // corrupted payload or checksum
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(is_invalid_data(decode(&corrupted, 100)));
+        let mut corrupted = frame.clone();
+        corrupted[5] ^= 1;
+        assert!(is_invalid_data(decode(&corrupted, 100)));
+
+        // oversized for the kind, even well below what other kinds may carry, and unknown kinds
+        let mut oversized = frame.clone();
+        oversized[1..5].copy_from_slice(&(u32::MAX).to_be_bytes());
+        assert!(is_invalid_data(decode(&oversized, 100)));
+        let mut oversized = encode(&message::Message::Verack);
+        oversized[1..5].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(is_invalid_data(decode(&oversized, 100)));
+        let mut unknown = frame;
+        unknown[0] = 200;
+        assert!(is_invalid_data(decode(&unknown, 100)));
+    }
+
+    #[test]
+    fn message_must_match_frame_kind() {
+        let msg = message::Message::Ping("hello".to_string());
+        assert!(matches!(parse_message(msg.kind(), &bincode::serialize(&msg).unwrap()), Ok(message::Message::Ping(_))));
+
+        // a ping framed as blocks gets past the size check, but not past parsing
+        let mut frame = encode(&msg);
+        frame[0] = message::Message::Blocks(vec![]).kind();
+        match decode(&frame, 100).unwrap() {
+            ReadResult::Message(kind, payload) => assert!(parse_message(kind, &payload).is_err()),
+            _ => panic!("expected a message"),
+        }
+        assert!(parse_message(msg.kind(), &[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn size_limits_fit_full_messages() {
+        let header = crate::block::test::generate_random_block(&H256::default()).header;
+        let headers = message::Message::Headers(vec![header; message::MAX_HEADERS]);
+        assert!(encode(&headers).len() - FRAME_HEADER_SIZE <= message::max_size(headers.kind()).unwrap());
+        let inv = message::Message::GetBlocks(vec![H256::default(); message::MAX_INV]);
+        assert!(encode(&inv).len() - FRAME_HEADER_SIZE <= message::max_size(inv.kind()).unwrap());
+        let addrs = vec![std::net::SocketAddr::from(([1, 2, 3, 4, 5, 6, 7, 8], 6000)); message::MAX_ADDR];
+        let addr = message::Message::Addr(addrs);
+        assert!(encode(&addr).len() - FRAME_HEADER_SIZE <= message::max_size(addr.kind()).unwrap());
+        let locator = message::Message::GetHeaders(Blockchain::new().locator(&H256::default()));
+        assert!(encode(&locator).len() - FRAME_HEADER_SIZE <= message::max_size(locator.kind()).unwrap());
+    }
+
+    #[test]
+    fn handshake_completes_on_version_and_verack() {
+        let mut handshake = Handshake::default();
+        assert!(!handshake.is_complete());
+        handshake.verack = true;
+        assert!(!handshake.is_complete());
+        handshake.version = Some(version(2));
+        assert!(handshake.is_complete());
+    }
+}
@@ -1,22 +1,37 @@
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use super::addrman::{AddrManager, PersistentPeers};
+use super::transport::{Stream, Transport};
+use crate::blockchain::Blockchain;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
-use mio::{self, net};
 use mio_extras::channel;
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
 use std::thread;
+use std::time::{Duration, Instant};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// How often the number of outbound connections is topped up
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for an outbound connection to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub fn new(
     addr: std::net::SocketAddr,
-    msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    msg_sink: cbchannel::Sender<(u8, Vec<u8>, peer::Handle)>,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    target_outbound: usize,
+    ban_time: Duration,
+    transport: Arc<dyn Transport>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
+    let addrman = Arc::new(Mutex::new(AddrManager::new()));
     let handle = Handle {
         control_chan: control_signal_sender,
+        addrman: Arc::clone(&addrman),
     };
     let ctx = Context {
         peers: slab::Slab::new(),
@@ -25,6 +40,14 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        blockchain: Arc::clone(blockchain),
+        addrman,
+        persistent: PersistentPeers::new(),
+        dialing: HashSet::new(),
+        target_outbound,
+        ban_time,
+        transport,
+        nonce: rand::random(),
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -36,7 +59,20 @@ pub struct Context {
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
-    new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    new_msg_chan: cbchannel::Sender<(u8, Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    addrman: Arc<Mutex<AddrManager>>,
+    /// Peers to stay connected to, reconnecting with backoff when they drop
+    persistent: PersistentPeers,
+    /// Addresses being dialed in the background
+    dialing: HashSet<std::net::SocketAddr>,
+    /// Number of outbound connections to keep, dialing gossiped addresses when short
+    target_outbound: usize,
+    /// How long a misbehaving peer stays banned
+    ban_time: Duration,
+    transport: Arc<dyn Transport>,
+    /// Sent in our version to detect connections to ourselves
+    nonce: u64,
     _handle: Handle,
 }
 
@@ -51,12 +87,13 @@ impl Context {
         Ok(())
     }
 
-    /// Register a TCP stream in the event loop, and initialize peer context.
+    /// Register a stream in the event loop, and initialize peer context.
     fn register(
         &mut self,
-        stream: net::TcpStream,
+        stream: Box<dyn Stream>,
         direction: peer::Direction,
     ) -> std::io::Result<peer::Handle> {
+        let version = self.local_version();
         // get a new slot in the connection set
         let vacant = self.peers.vacant_entry();
         let key: usize = vacant.key();
@@ -74,12 +111,12 @@ impl Context {
 
         // register the new connection
         self.poll.register(
-            &stream,
+            &*stream,
             socket_token,
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(key, stream, direction)?;
 
         // register the writer queue
         self.poll.register(
@@ -89,6 +126,9 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // both sides open the handshake by announcing their version
+        handle.write(message::Message::Version(version));
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
@@ -97,22 +137,69 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
-        let mio_stream = net::TcpStream::from_stream(stream)?;
-        self.register(mio_stream, peer::Direction::Outgoing)
+    /// Dial a peer on a background thread, so that the event loop keeps serving the other peers
+    /// while the connection is established. The stream comes back as a `Dialed` signal, and the
+    /// peer handle is sent to `result_chan` once it is registered.
+    fn dial(&mut self, addr: std::net::SocketAddr, result_chan: Option<cbchannel::Sender<std::io::Result<peer::Handle>>>) {
+        // persistent peers and explicit requests are not filtered by the address manager
+        let now = Instant::now();
+        if self.addrman.lock().unwrap().is_banned(&addr.ip(), now) {
+            debug!("Not dialing banned peer {}", addr);
+            // back off as for a failed dial, rather than asking again on every check
+            self.persistent.failed(&addr, now);
+            if let Some(result_chan) = result_chan {
+                let _ = result_chan.send(Err(std::io::Error::other("peer is banned")));
+            }
+            return;
+        }
+        debug!("Dialing peer {}", addr);
+        self.addrman.lock().unwrap().mark_tried(addr, now);
+        self.dialing.insert(addr);
+        let control_chan = self._handle.control_chan.clone();
+        let transport = Arc::clone(&self.transport);
+        let spawned = thread::Builder::new()
+            .name("p2p-dial".to_string())
+            .spawn(move || {
+                let stream = transport.connect(addr, CONNECT_TIMEOUT);
+                // the server may have shut down in the meantime
+                let _ = control_chan.send(ControlSignal::Dialed(DialResult { addr, stream, result_chan }));
+            });
+        if let Err(e) = spawned {
+            error!("Error spawning a thread to dial peer {}: {}", addr, e);
+            self.dialing.remove(&addr);
+        }
+    }
+
+    /// Register a peer whose background dial finished
+    fn dialed(&mut self, result: DialResult) {
+        let DialResult { addr, stream, result_chan } = result;
+        self.dialing.remove(&addr);
+        let handle = stream.and_then(|stream| self.register(stream, peer::Direction::Outgoing));
+        match &handle {
+            Ok(_) => info!("Connected to outgoing peer {}", addr),
+            Err(e) if self.persistent.contains(&addr) => {
+                warn!("Error connecting to persistent peer {}: {}", addr, e);
+                self.persistent.failed(&addr, Instant::now());
+            }
+            Err(e) => debug!("Error connecting to peer {}: {}", addr, e),
+        }
+        if let Some(result_chan) = result_chan {
+            // the requester may have given up
+            let _ = result_chan.send(handle);
+        }
     }
 
     /// Accept an incoming peer and register it
     fn accept(
         &mut self,
-        stream: net::TcpStream,
+        stream: Box<dyn Stream>,
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.addrman.lock().unwrap().is_banned(&addr.ip(), Instant::now()) {
+            info!("Refusing connection from banned peer {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -128,13 +215,53 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
-                req.result_chan.send(handle).unwrap();
+                self.dial(req.addr, Some(req.result_chan));
+            }
+            ControlSignal::Dialed(result) => {
+                trace!("Processing Dialed command");
+                self.dialed(result);
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    // peers that have not finished the handshake only get handshake messages
+                    let peer = &self.peers[*peer_id];
+                    if peer.handshake.is_complete() {
+                        peer.handle.write(msg.clone());
+                    }
+                }
+            }
+            ControlSignal::AddPersistentPeer(addr) => {
+                trace!("Processing AddPersistentPeer command");
+                self.persistent.add(addr, Instant::now());
+                self.maintain_persistent();
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self.peer_list.iter().map(|id| self.peers[*id].info()).collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::Disconnect(peer_id, result_chan) => {
+                trace!("Processing Disconnect command");
+                let found = match self.peers.get(peer_id) {
+                    Some(peer) => {
+                        info!("Disconnecting peer {} on request", peer.addr);
+                        if peer.direction == peer::Direction::Outgoing {
+                            // we were asked to drop it, so don't reconnect
+                            self.persistent.remove(&peer.addr);
+                        }
+                        self.remove_peer(peer_id);
+                        true
+                    }
+                    None => false,
+                };
+                result_chan.send(found).unwrap();
+            }
+            ControlSignal::Misbehaving(peer_id, addr, misbehavior) => {
+                trace!("Processing Misbehaving command");
+                // the slot may have been reused by another peer since the report
+                if self.peers.get(peer_id).map(|p| p.addr) == Some(addr) {
+                    self.misbehaving(peer_id, misbehavior);
                 }
             }
         }
@@ -148,7 +275,7 @@ impl Context {
         let socket_token = mio::Token(peer_id * 2);
         // register for writable event
         self.poll.reregister(
-            &peer.stream,
+            &*peer.stream,
             socket_token,
             mio::Ready::readable() | mio::Ready::writable(),
             mio::PollOpt::edge(),
@@ -158,15 +285,13 @@ impl Context {
 
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
         // we are using edge-triggered events, loop until block
-        let peer = &mut self.peers[peer_id];
         loop {
+            let peer = &mut self.peers[peer_id];
             match peer.reader.read() {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -174,10 +299,17 @@ impl Context {
                     // no full message has been received
                     continue;
                 }
-                Ok(ReadResult::Message(m)) => {
+                Ok(ReadResult::Message(kind, m)) => {
                     trace!("Peer {} yield message", peer_id);
-                    // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    peer.bytes_received += (peer::FRAME_HEADER_SIZE + m.len()) as u64;
+                    peer.last_seen = Instant::now();
+                    // we just received a full message, the worker only sees it after the handshake
+                    if peer.handshake.is_complete() {
+                        self.new_msg_chan.send((kind, m, peer.handle.clone())).unwrap();
+                    } else if !self.process_handshake(peer_id, kind, &m) {
+                        self.remove_peer(peer_id);
+                        break;
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -187,9 +319,11 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        if e.kind() == std::io::ErrorKind::InvalidData {
+                            // a framing error, the rest of the stream can't be trusted
+                            self.misbehaving(peer_id, peer::Misbehavior::MalformedMessage);
+                        }
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -198,6 +332,159 @@ impl Context {
         Ok(())
     }
 
+    /// Handle a message received before the handshake completed. Returns false if the peer
+    /// should be disconnected.
+    fn process_handshake(&mut self, peer_id: usize, kind: u8, msg: &[u8]) -> bool {
+        let local = self.local_version();
+        let peer = &mut self.peers[peer_id];
+        let msg = match peer::parse_message(kind, msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Malformed handshake message from peer {}: {}", peer.addr, e);
+                self.misbehaving(peer_id, peer::Misbehavior::MalformedMessage);
+                return false;
+            }
+        };
+        match msg {
+            message::Message::Version(version) => {
+                if peer.handshake.version.is_some() {
+                    warn!("Peer {} sent its version twice, disconnecting", peer.addr);
+                    return false;
+                }
+                if let Err(e) = peer::check_version(&local, &version) {
+                    warn!("Handshake with peer {} failed, disconnecting: {:?}", peer.addr, e);
+                    if let peer::Direction::Outgoing = peer.direction {
+                        // not a node we can talk to, don't dial it again
+                        self.addrman.lock().unwrap().remove(&peer.addr);
+                    }
+                    return false;
+                }
+                debug!(
+                    "Peer {} runs protocol version {} at height {}",
+                    peer.addr, version.version, version.best_height
+                );
+                peer.handshake.version = Some(version);
+                peer.handle.write(message::Message::Verack);
+            }
+            message::Message::Verack => {
+                peer.handshake.verack = true;
+            }
+            _ => {
+                warn!("Peer {} sent a message before the handshake, disconnecting", peer.addr);
+                return false;
+            }
+        }
+        if peer.handshake.is_complete() {
+            info!("Handshake with peer {} complete", peer.addr);
+            match peer.direction {
+                peer::Direction::Outgoing => {
+                    self.addrman.lock().unwrap().mark_good(peer.addr, Instant::now());
+                    self.persistent.connected(&peer.addr);
+                    // learn about more peers from the ones we chose to connect to
+                    peer.handle.write(message::Message::GetAddr);
+                }
+                peer::Direction::Incoming => {
+                    self.addrman.lock().unwrap().add(peer.listen_addr().unwrap(), Instant::now());
+                }
+            }
+            // catch up with a peer that is ahead, headers first
+            let blockchain = self.blockchain.lock().unwrap();
+            let best_header = blockchain.best_header();
+            let peer_height = peer.handshake.version.as_ref().unwrap().best_height;
+            if peer_height > blockchain.header_height(&best_header).unwrap() {
+                peer.handle.write(message::Message::GetHeaders(blockchain.locator(&best_header)));
+            }
+        }
+        true
+    }
+
+    /// The version we announce to peers
+    fn local_version(&self) -> message::Version {
+        let blockchain = self.blockchain.lock().unwrap();
+        message::Version {
+            version: message::PROTOCOL_VERSION,
+            magic: message::NETWORK_MAGIC,
+            genesis: blockchain.genesis(),
+            best_height: blockchain.tip_height,
+            services: message::SERVICE_FULL_NODE,
+            listen_port: self.addr.port(),
+            nonce: self.nonce,
+        }
+    }
+
+    /// Dial known addresses until we have the target number of outbound connections
+    fn maintain_outbound(&mut self) {
+        let outbound = self
+            .peers
+            .iter()
+            .filter(|(_, p)| matches!(p.direction, peer::Direction::Outgoing))
+            .count()
+            + self.dialing.len();
+        let mut connected: HashSet<std::net::SocketAddr> =
+            self.peers.iter().filter_map(|(_, p)| p.listen_addr()).collect();
+        connected.extend(self.dialing.iter().cloned());
+        for _ in outbound..self.target_outbound {
+            let addr = match self.addrman.lock().unwrap().select(&connected, Instant::now()) {
+                Some(addr) => addr,
+                None => break,
+            };
+            connected.insert(addr);
+            self.dial(addr, None);
+        }
+    }
+
+    /// Dial the persistent peers that are not connected and whose backoff has passed
+    fn maintain_persistent(&mut self) {
+        let now = Instant::now();
+        let connected: HashSet<std::net::SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.direction == peer::Direction::Outgoing)
+            .map(|(_, p)| p.addr)
+            .chain(self.dialing.iter().cloned())
+            .collect();
+        for addr in self.persistent.due(&connected, now) {
+            self.dial(addr, None);
+        }
+    }
+
+    /// Raise the misbehavior score of a peer, and disconnect and ban it once it crosses the threshold
+    fn misbehaving(&mut self, peer_id: usize, misbehavior: peer::Misbehavior) {
+        let peer = &mut self.peers[peer_id];
+        peer.misbehavior += misbehavior.score();
+        warn!("Peer {} misbehaved: {:?}, score {}", peer.addr, misbehavior, peer.misbehavior);
+        if peer.misbehavior >= peer::BAN_THRESHOLD {
+            let ip = peer.addr.ip();
+            warn!("Banning {} for {} seconds", ip, self.ban_time.as_secs());
+            self.addrman.lock().unwrap().ban(ip, Instant::now() + self.ban_time);
+            // every connection from the host goes, not just this one
+            let banned: Vec<usize> = self
+                .peer_list
+                .iter()
+                .cloned()
+                .filter(|id| self.peers[*id].addr.ip() == ip)
+                .collect();
+            for id in banned {
+                self.remove_peer(id);
+            }
+        }
+    }
+
+    /// Remove a peer from the connection set, which closes its connection
+    fn remove_peer(&mut self, peer_id: usize) {
+        // a ban may already have removed it
+        if !self.peers.contains(peer_id) {
+            return;
+        }
+        let peer = self.peers.remove(peer_id);
+        debug!("Disconnected from peer {}", peer.addr);
+        if peer.direction == peer::Direction::Outgoing && self.persistent.contains(&peer.addr) {
+            self.persistent.failed(&peer.addr, Instant::now());
+        }
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
     fn process_writable(&mut self, peer_id: usize) -> std::io::Result<()> {
         let peer = &mut self.peers[peer_id];
         match peer.writer.write() {
@@ -208,7 +495,7 @@ impl Context {
                 let writer_token = mio::Token(peer_id * 2 + 1);
                 // we've done writing. no longer interested.
                 self.poll.reregister(
-                    &peer.stream,
+                    &*peer.stream,
                     socket_token,
                     mio::Ready::readable(),
                     mio::PollOpt::edge(),
@@ -224,16 +511,14 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
                 warn!("Peer {} outgoing queue closed", peer_id);
                 let socket_token = mio::Token(peer_id * 2);
                 self.poll.reregister(
-                    &peer.stream,
+                    &*peer.stream,
                     socket_token,
                     mio::Ready::readable(),
                     mio::PollOpt::edge(),
@@ -246,9 +531,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -258,12 +541,12 @@ impl Context {
     /// The main event loop of the server.
     fn listen(&mut self) -> std::io::Result<()> {
         // bind server to passed addr and register to the poll
-        let server = net::TcpListener::bind(&self.addr)?;
+        let server = self.transport.bind(self.addr)?;
 
         // token for new incoming connection
         const INCOMING: mio::Token = mio::Token(std::usize::MAX - 1);
         self.poll.register(
-            &server,
+            &*server,
             INCOMING,
             mio::Ready::readable(),
             mio::PollOpt::edge(),
@@ -282,9 +565,16 @@ impl Context {
 
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
+        let mut last_maintain = Instant::now();
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(MAINTAIN_INTERVAL))?;
+
+            if last_maintain.elapsed() >= MAINTAIN_INTERVAL {
+                self.maintain_persistent();
+                self.maintain_outbound();
+                last_maintain = Instant::now();
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -353,6 +643,9 @@ impl Context {
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
                                 self.register_write_interest(peer_id)?;
                             }
                             _ => unreachable!(),
@@ -367,6 +660,7 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    addrman: Arc<Mutex<AddrManager>>,
 }
 
 impl Handle {
@@ -382,16 +676,68 @@ impl Handle {
         receiver.recv().unwrap()
     }
 
+    /// Connect to a peer and keep reconnecting to it, with backoff, whenever the connection fails
+    /// or drops
+    pub fn add_persistent_peer(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::AddPersistentPeer(addr))
+            .unwrap();
+    }
+
+    /// The connected peers
+    pub fn peers(&self) -> Vec<peer::PeerInfo> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::ListPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Drop the connection to a peer, and stop reconnecting to it. Returns false if there is no
+    /// peer with this id.
+    pub fn disconnect(&self, peer_id: usize) -> bool {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::Disconnect(peer_id, sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// The addresses known to the server, shared with the workers handling address gossip
+    pub fn addrman(&self) -> &Arc<Mutex<AddrManager>> {
+        &self.addrman
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         self.control_chan
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Report a peer for breaking the protocol
+    pub fn misbehaving(&self, peer: &peer::Handle, misbehavior: peer::Misbehavior) {
+        self.control_chan
+            .send(ControlSignal::Misbehaving(peer.id(), peer.addr(), misbehavior))
+            .unwrap();
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    AddPersistentPeer(std::net::SocketAddr),
+    ListPeers(cbchannel::Sender<Vec<peer::PeerInfo>>),
+    Disconnect(usize, cbchannel::Sender<bool>),
+    Misbehaving(usize, std::net::SocketAddr, peer::Misbehavior),
+    /// A background dial finished
+    Dialed(DialResult),
+}
+
+struct DialResult {
+    addr: std::net::SocketAddr,
+    stream: std::io::Result<Box<dyn Stream>>,
+    /// Where to send the peer handle, if someone asked for the connection
+    result_chan: Option<cbchannel::Sender<std::io::Result<peer::Handle>>>,
 }
 
 struct ConnectRequest {
@@ -0,0 +1,773 @@
+//! An in-process network of full nodes for tests. Every node runs the real P2P server, worker
+//! and miner against its own blockchain, statechain and mempool. The servers talk over an
+//! in-memory transport instead of TCP, so the handshake, framing, relay and banning run as they
+//! do between processes, while the router between them adds configurable latency, loss and
+//! partitions.
+//!
+//! Faults are drawn from a seeded generator on a single router thread, so the same sequence of
+//! frames meets the same faults on every run. The node threads still interleave freely.
+
+use super::compact::CompactBlock;
+use super::message::Message;
+use super::peer::{self, FRAME_HEADER_SIZE};
+use super::server::{self, Handle as ServerHandle};
+use super::transport::{Listener, Stream, Transport};
+use super::worker;
+use crate::block::{Block, Content, Header};
+use crate::blockchain::Blockchain;
+use crate::consensus::{self, Params};
+use crate::crypto::hash::{Hashable, H160, H256};
+use crate::crypto::merkle::MerkleTree;
+use crate::miner;
+use crate::storage::MemoryStore;
+use crate::transaction::{Mempool, SignedTransaction, State, StateChain};
+use crossbeam::channel as cbchannel;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the router moves frames along
+const ROUTER_INTERVAL: Duration = Duration::from_millis(1);
+/// Port every simulated node listens on
+const LISTEN_PORT: u16 = 6000;
+/// How long a simulated node bans a misbehaving peer
+const BAN_TIME: Duration = Duration::from_secs(60 * 60);
+/// How long to wait for the servers to come up, and for a handshake to complete
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Behavior of the links between simulated nodes
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Delay of every frame
+    pub latency: Duration,
+    /// Up to this much extra delay, drawn for each frame
+    pub jitter: Duration,
+    /// Probability that a frame is lost
+    pub drop_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            drop_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Seeded source of link faults
+struct Faults {
+    config: Config,
+    rng: StdRng,
+}
+
+impl Faults {
+    fn new(config: Config) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        Faults { config, rng }
+    }
+
+    /// Delay of the next frame, or None if it is lost
+    fn next(&mut self) -> Option<Duration> {
+        let lost = self.rng.gen::<f64>() < self.config.drop_rate;
+        let jitter = self.config.jitter.mul_f64(self.rng.gen::<f64>());
+        if lost {
+            None
+        } else {
+            Some(self.config.latency + jitter)
+        }
+    }
+}
+
+/// A simulated full node
+pub struct Node {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub statechain: Arc<Mutex<StateChain>>,
+    pub server: ServerHandle,
+    pub miner: miner::Handle,
+    pub address: H160,
+}
+
+impl Node {
+    pub fn tip(&self) -> H256 {
+        self.blockchain.lock().unwrap().tip()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.blockchain.lock().unwrap().tip_height
+    }
+
+    /// Mine a block holding only a coinbase on top of the tip, and announce it as the miner does
+    pub fn mine(&self) -> Block {
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let mut statechain = self.statechain.lock().unwrap();
+        let parent = blockchain.tip();
+        let height = blockchain.tip_height + 1;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let timestamp = std::cmp::max(now, blockchain.median_time_past(&parent) + 1);
+        let difficulty = blockchain.next_difficulty(&parent);
+        let reward = consensus::block_subsidy(&blockchain.params, height);
+        let content = vec![SignedTransaction::coinbase(height, self.address, reward)];
+        let merkle_root = MerkleTree::new(&content).root();
+        let mut header = Header { parent, nonce: 0, difficulty, timestamp, merkle_root };
+        while header.hash() > difficulty {
+            header.nonce += 1;
+        }
+        let block = Block { header, content: Content { content } };
+        blockchain.insert(&block);
+        let (disconnected, connected) = statechain.sync(&blockchain);
+        self.mempool.lock().unwrap().sync(&blockchain, &statechain.state, &disconnected, &connected);
+        self.server.broadcast(Message::CompactBlock(CompactBlock::new(&block, rand::random())));
+        block
+    }
+}
+
+/// One direction of an in-memory connection
+struct Pipe {
+    /// Bytes written and not yet taken by the router
+    written: Vec<u8>,
+    /// Bytes delivered by the router and not yet read
+    delivered: VecDeque<u8>,
+    /// Either end hung up, or the router cut the connection
+    closed: bool,
+    /// Readiness of the reading end
+    readiness: SetReadiness,
+}
+
+impl Pipe {
+    fn new(readiness: SetReadiness) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Pipe { written: vec![], delivered: VecDeque::new(), closed: false, readiness }))
+    }
+
+    /// Tell the reading end that there is something to read, or that the connection closed
+    fn wake(&self) {
+        // writes are never refused, so the stream stays writable
+        let _ = self.readiness.set_readiness(Ready::readable() | Ready::writable());
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake();
+    }
+}
+
+/// One end of an in-memory connection, shared by the handles of its stream
+struct End {
+    peer_addr: SocketAddr,
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    registration: Registration,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        // as with a socket, the connection closes with the last handle
+        self.incoming.lock().unwrap().close();
+        self.outgoing.lock().unwrap().close();
+    }
+}
+
+struct MemoryStream(Arc<End>);
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.0.incoming.lock().unwrap();
+        if pipe.delivered.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+            // the router makes the stream readable again when it delivers more, which it can only
+            // do once we let go of the pipe
+            let _ = pipe.readiness.set_readiness(Ready::writable());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let size = std::cmp::min(buf.len(), pipe.delivered.len());
+        for (byte, delivered) in buf.iter_mut().zip(pipe.delivered.drain(..size)) {
+            *byte = delivered;
+        }
+        Ok(size)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.0.outgoing.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for MemoryStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.0.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.0.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.0.registration.deregister(poll)
+    }
+}
+
+impl Stream for MemoryStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(MemoryStream(Arc::clone(&self.0))))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.0.peer_addr)
+    }
+}
+
+/// Connections waiting to be accepted by a node
+struct Backlog {
+    streams: VecDeque<(MemoryStream, SocketAddr)>,
+    readiness: SetReadiness,
+}
+
+struct MemoryListener {
+    addr: SocketAddr,
+    backlog: Arc<Mutex<Backlog>>,
+    registration: Registration,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> io::Result<(Box<dyn Stream>, SocketAddr)> {
+        let mut backlog = self.backlog.lock().unwrap();
+        match backlog.streams.pop_front() {
+            Some((stream, addr)) => Ok((Box::new(stream), addr)),
+            None => {
+                let _ = backlog.readiness.set_readiness(Ready::empty());
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Evented for MemoryListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+/// The transport of a simulated node, which connects through the router
+struct Memory {
+    router: Arc<Mutex<Router>>,
+    node: usize,
+}
+
+impl Transport for Memory {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let mut router = self.router.lock().unwrap();
+        if router.listeners.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (registration, readiness) = Registration::new2();
+        let backlog = Arc::new(Mutex::new(Backlog { streams: VecDeque::new(), readiness }));
+        router.listeners.insert(addr, (self.node, Arc::clone(&backlog)));
+        Ok(Box::new(MemoryListener { addr, backlog, registration }))
+    }
+
+    fn connect(&self, addr: SocketAddr, _timeout: Duration) -> io::Result<Box<dyn Stream>> {
+        let stream = self.router.lock().unwrap().connect(self.node, addr, Instant::now())?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// A connection between two nodes
+struct Connection {
+    /// The dialing node and the accepting node
+    nodes: (usize, usize),
+    /// The pipe from the dialing node to the accepting node, and the one back
+    pipes: [Arc<Mutex<Pipe>>; 2],
+    /// Bytes taken from each pipe that don't make up a whole frame yet
+    partial: [Vec<u8>; 2],
+    /// When the last frame each way is delivered, so that the connection stays in order as TCP does
+    last_delivery: [Instant; 2],
+}
+
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.pipes.iter().any(|pipe| pipe.lock().unwrap().closed)
+    }
+
+    fn close(&self) {
+        for pipe in self.pipes.iter() {
+            pipe.lock().unwrap().close();
+        }
+    }
+}
+
+/// A frame on its way, ordered by delivery time and then by sending order, with its connection
+/// and direction
+type InFlight = Reverse<(Instant, u64, u64, usize, Vec<u8>)>;
+
+struct Router {
+    faults: Faults,
+    /// The listening node and its backlog, by address
+    listeners: BTreeMap<SocketAddr, (usize, Arc<Mutex<Backlog>>)>,
+    connections: BTreeMap<u64, Connection>,
+    next_connection: u64,
+    /// Side of the partition each node is on, nodes on different sides can't connect
+    sides: Vec<usize>,
+    /// Connections the partition cut, by dialing and accepting node, to make again once it heals
+    severed: Vec<(usize, usize)>,
+    in_flight: BinaryHeap<InFlight>,
+    sent: u64,
+    dropped: u64,
+}
+
+fn node_ip(node: usize) -> IpAddr {
+    IpAddr::from([10, 0, (node / 256) as u8, (node % 256) as u8])
+}
+
+/// Address a simulated node listens on
+fn node_addr(node: usize) -> SocketAddr {
+    SocketAddr::new(node_ip(node), LISTEN_PORT)
+}
+
+/// Take the whole frames off the front of `bytes`
+fn split_frames(bytes: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let mut frames = vec![];
+    while bytes.len() >= FRAME_HEADER_SIZE {
+        let length = u32::from_be_bytes(bytes[1..5].try_into().unwrap()) as usize;
+        if bytes.len() < FRAME_HEADER_SIZE + length {
+            break;
+        }
+        let rest = bytes.split_off(FRAME_HEADER_SIZE + length);
+        frames.push(std::mem::replace(bytes, rest));
+    }
+    frames
+}
+
+impl Router {
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        self.sides[from] == self.sides[to]
+    }
+
+    /// Open a connection from `node` to the node listening on `addr`, and queue the other end on
+    /// that node's listener
+    fn connect(&mut self, node: usize, addr: SocketAddr, now: Instant) -> io::Result<MemoryStream> {
+        let (to, backlog) = match self.listeners.get(&addr) {
+            Some((to, backlog)) if self.reachable(node, *to) => (*to, Arc::clone(backlog)),
+            _ => return Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+        self.next_connection += 1;
+        // each connection gets its own port on the dialing node
+        let local_addr = SocketAddr::new(node_ip(node), (49152 + self.next_connection % 16384) as u16);
+        let (dialer_registration, dialer_readiness) = Registration::new2();
+        let (acceptor_registration, acceptor_readiness) = Registration::new2();
+        dialer_readiness.set_readiness(Ready::writable())?;
+        acceptor_readiness.set_readiness(Ready::writable())?;
+        let forward = Pipe::new(acceptor_readiness);
+        let backward = Pipe::new(dialer_readiness);
+        let dialer = End {
+            peer_addr: addr,
+            incoming: Arc::clone(&backward),
+            outgoing: Arc::clone(&forward),
+            registration: dialer_registration,
+        };
+        let acceptor = End {
+            peer_addr: local_addr,
+            incoming: Arc::clone(&forward),
+            outgoing: Arc::clone(&backward),
+            registration: acceptor_registration,
+        };
+        {
+            let mut backlog = backlog.lock().unwrap();
+            backlog.streams.push_back((MemoryStream(Arc::new(acceptor)), local_addr));
+            backlog.readiness.set_readiness(Ready::readable())?;
+        }
+        let connection = Connection {
+            nodes: (node, to),
+            pipes: [forward, backward],
+            partial: [vec![], vec![]],
+            last_delivery: [now; 2],
+        };
+        self.connections.insert(self.next_connection, connection);
+        Ok(MemoryStream(Arc::new(dialer)))
+    }
+
+    /// Close the open connections whose dialing and accepting node match, and return those nodes
+    fn cut<F: Fn(usize, usize) -> bool>(&mut self, matches: F) -> Vec<(usize, usize)> {
+        let mut cut = vec![];
+        for connection in self.connections.values() {
+            let (a, b) = connection.nodes;
+            if matches(a, b) && !connection.is_closed() {
+                connection.close();
+                cut.push((a, b));
+            }
+        }
+        cut
+    }
+
+    /// Send the frames written since the last step and deliver those due
+    fn step(&mut self, now: Instant) {
+        let mut sent = vec![];
+        for (&id, connection) in self.connections.iter_mut() {
+            for direction in 0..2 {
+                let written = std::mem::take(&mut connection.pipes[direction].lock().unwrap().written);
+                connection.partial[direction].extend(written);
+                for frame in split_frames(&mut connection.partial[direction]) {
+                    sent.push((id, direction, frame));
+                }
+            }
+        }
+        for (id, direction, frame) in sent {
+            self.sent += 1;
+            let delay = match self.faults.next() {
+                Some(delay) => delay,
+                None => {
+                    self.dropped += 1;
+                    continue;
+                }
+            };
+            let connection = self.connections.get_mut(&id).unwrap();
+            let time = std::cmp::max(now + delay, connection.last_delivery[direction]);
+            connection.last_delivery[direction] = time;
+            self.in_flight.push(Reverse((time, self.sent, id, direction, frame)));
+        }
+
+        while let Some(Reverse((time, ..))) = self.in_flight.peek() {
+            if *time > now {
+                break;
+            }
+            let Reverse((_, _, id, direction, frame)) = self.in_flight.pop().unwrap();
+            // the connection may have closed while the frame was on its way
+            let delivered = match self.connections.get(&id) {
+                Some(connection) => {
+                    let mut pipe = connection.pipes[direction].lock().unwrap();
+                    if !pipe.closed {
+                        pipe.delivered.extend(frame);
+                        pipe.wake();
+                    }
+                    !pipe.closed
+                }
+                None => false,
+            };
+            if !delivered {
+                self.dropped += 1;
+            }
+        }
+        self.connections.retain(|_, connection| !connection.is_closed());
+    }
+}
+
+/// A network of simulated nodes. The nodes stop when it is dropped.
+pub struct Network {
+    pub nodes: Vec<Node>,
+    router: Arc<Mutex<Router>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Network {
+    /// Start `size` unconnected nodes sharing the consensus parameters
+    pub fn new(size: usize, params: Params, config: Config) -> Self {
+        let router = Router {
+            faults: Faults::new(config),
+            listeners: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            next_connection: 0,
+            sides: vec![0; size],
+            severed: vec![],
+            in_flight: BinaryHeap::new(),
+            sent: 0,
+            dropped: 0,
+        };
+        let router = Arc::new(Mutex::new(router));
+        let mut nodes = vec![];
+        for i in 0..size {
+            let blockchain = Blockchain::open(Box::new(MemoryStore), params.clone()).unwrap();
+            let statechain = StateChain::new(blockchain.genesis(), State::new());
+            let blockchain = Arc::new(Mutex::new(blockchain));
+            let statechain = Arc::new(Mutex::new(statechain));
+            let mempool = Arc::new(Mutex::new(Mempool::new()));
+            let mut address = [0; 32];
+            address[..8].copy_from_slice(&(i as u64 + 1).to_be_bytes());
+            let address = H160::from(address);
+
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let transport = Arc::new(Memory { router: Arc::clone(&router), node: i });
+            // no outbound target, the tests choose whom each node connects to
+            let (server_ctx, server) = server::new(node_addr(i), msg_tx, &blockchain, 0, BAN_TIME, transport).unwrap();
+            server_ctx.start().unwrap();
+            worker::new(2, msg_rx, &server, &blockchain, &mempool, &statechain, address).start();
+            let (miner_ctx, miner) = miner::new(&server, &blockchain, &mempool, &statechain, address, 1);
+            miner_ctx.start();
+
+            nodes.push(Node { blockchain, mempool, statechain, server, miner, address });
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        {
+            let router = Arc::clone(&router);
+            let running = Arc::clone(&running);
+            thread::Builder::new()
+                .name("sim-router".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::SeqCst) {
+                        router.lock().unwrap().step(Instant::now());
+                        thread::sleep(ROUTER_INTERVAL);
+                    }
+                })
+                .unwrap();
+        }
+        let network = Network { nodes, router, running };
+        // each server binds its listener on its own thread
+        let bound = network.wait_for(SETUP_TIMEOUT, |n| n.router.lock().unwrap().listeners.len() == size);
+        assert!(bound, "simulated nodes did not start listening");
+        network
+    }
+
+    /// Have node `a` dial node `b`, and wait for their handshake to complete
+    pub fn connect(&self, a: usize, b: usize) {
+        let peer = self.nodes[a].server.connect(node_addr(b)).unwrap();
+        let ready = |node: usize, matches: &dyn Fn(&peer::PeerInfo) -> bool| {
+            self.nodes[node].server.peers().iter().any(|info| info.ready && matches(info))
+        };
+        let complete = self.wait_for(SETUP_TIMEOUT, |_| {
+            ready(a, &|info| info.id == peer.id()) && ready(b, &|info| info.addr.ip() == node_ip(a))
+        });
+        assert!(complete, "handshake between nodes {} and {} did not complete", a, b);
+    }
+
+    /// Close the connections between two nodes
+    pub fn disconnect(&self, a: usize, b: usize) {
+        self.router.lock().unwrap().cut(|x, y| (x, y) == (a, b) || (x, y) == (b, a));
+    }
+
+    /// Cut the given nodes off from the rest: the connections across the cut close, and new ones
+    /// are refused
+    pub fn partition(&self, side: &[usize]) {
+        let mut router = self.router.lock().unwrap();
+        for (node, s) in router.sides.iter_mut().enumerate() {
+            *s = if side.contains(&node) { 1 } else { 0 };
+        }
+        let sides = router.sides.clone();
+        let severed = router.cut(|a, b| sides[a] != sides[b]);
+        router.severed.extend(severed);
+    }
+
+    /// Rejoin all nodes, and make again the connections the partition cut. The nodes then catch
+    /// up with each other through their handshakes.
+    pub fn heal(&self) {
+        let severed = {
+            let mut router = self.router.lock().unwrap();
+            router.sides.iter_mut().for_each(|s| *s = 0);
+            std::mem::take(&mut router.severed)
+        };
+        for (a, b) in severed {
+            self.connect(a, b);
+        }
+    }
+
+    /// Frames sent and lost so far
+    pub fn traffic(&self) -> (u64, u64) {
+        let router = self.router.lock().unwrap();
+        (router.sent, router.dropped)
+    }
+
+    /// Misbehavior score a node holds against its connected peers, in total
+    pub fn misbehavior(&self, node: usize) -> u32 {
+        self.nodes[node].server.peers().iter().map(|info| info.misbehavior).sum()
+    }
+
+    /// Wait until the condition holds, returns false if it still does not after `timeout`
+    pub fn wait_for<F: Fn(&Network) -> bool>(&self, timeout: Duration, condition: F) -> bool {
+        let start = Instant::now();
+        while !condition(self) {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        let tip = self.nodes[0].tip();
+        self.nodes.iter().all(|node| node.tip() == tip)
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        for node in self.nodes.iter() {
+            node.miner.stop();
+        }
+        // the servers drop their peers once the connections close
+        self.router.lock().unwrap().cut(|_, _| true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(20);
+
+    #[test]
+    fn faults_are_seeded() {
+        let config = Config { jitter: Duration::from_millis(5), drop_rate: 0.3, seed: 7, ..Default::default() };
+        let draws = |config: &Config| -> Vec<Option<Duration>> {
+            let mut faults = Faults::new(config.clone());
+            (0..100).map(|_| faults.next()).collect()
+        };
+        let first = draws(&config);
+        assert_eq!(first, draws(&config));
+        assert_ne!(first, draws(&Config { seed: 8, ..config.clone() }));
+        let lost = first.iter().filter(|d| d.is_none()).count();
+        assert!(lost > 10 && lost < 50);
+        for delay in first.iter().flatten() {
+            assert!(*delay >= config.latency && *delay <= config.latency + config.jitter);
+        }
+        assert!(draws(&Config::default()).iter().all(|d| *d == Some(Config::default().latency)));
+    }
+
+    #[test]
+    fn blocks_propagate() {
+        let network = Network::new(3, Params::default(), Config { jitter: Duration::from_millis(5), ..Default::default() });
+        network.connect(0, 1);
+        network.connect(1, 2);
+        for _ in 0..3 {
+            network.nodes[0].mine();
+        }
+        assert!(network.wait_for(TIMEOUT, |n| n.converged() && n.nodes[2].height() == 3));
+        network.nodes[2].mine();
+        assert!(network.wait_for(TIMEOUT, |n| n.converged() && n.nodes[0].height() == 4));
+        for node in 0..3 {
+            assert_eq!(network.misbehavior(node), 0);
+        }
+        assert_eq!(network.traffic().1, 0);
+    }
+
+    #[test]
+    fn partition_reorgs_to_heavier_chain() {
+        let network = Network::new(3, Params::default(), Config::default());
+        network.connect(0, 1);
+        network.connect(1, 2);
+        network.nodes[0].mine();
+        assert!(network.wait_for(TIMEOUT, |n| n.converged() && n.nodes[2].height() == 1));
+
+        network.partition(&[0]);
+        network.nodes[0].mine();
+        network.nodes[0].mine();
+        for _ in 0..3 {
+            network.nodes[2].mine();
+        }
+        assert!(network.wait_for(TIMEOUT, |n| n.nodes[1].height() == 4));
+        assert_eq!(network.nodes[0].height(), 3);
+        assert!(!network.converged());
+
+        network.heal();
+        assert!(network.wait_for(TIMEOUT, |n| n.converged()));
+        assert_eq!(network.nodes[0].height(), 4);
+        assert_eq!(network.nodes[0].tip(), network.nodes[2].tip());
+        for node in 0..3 {
+            assert_eq!(network.misbehavior(node), 0);
+        }
+    }
+
+    #[test]
+    fn handshake_reports_peers() {
+        let network = Network::new(2, Params::default(), Config::default());
+        network.nodes[1].mine();
+        network.connect(0, 1);
+        let peers = network.nodes[0].server.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, node_addr(1));
+        assert_eq!(peers[0].direction, peer::Direction::Outgoing);
+        assert_eq!(peers[0].best_height, Some(1));
+        assert!(peers[0].bytes_received > 0 && peers[0].bytes_sent > 0);
+        let peers = network.nodes[1].server.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr.ip(), node_ip(0));
+        assert_eq!(peers[0].direction, peer::Direction::Incoming);
+        assert_eq!(peers[0].best_height, Some(0));
+        // the node that is behind catches up from the handshake
+        assert!(network.wait_for(TIMEOUT, |n| n.converged()));
+    }
+
+    #[test]
+    fn misbehaving_peer_is_banned() {
+        let network = Network::new(2, Params::default(), Config::default());
+        network.connect(0, 1);
+        let header = {
+            let blockchain = network.nodes[0].blockchain.lock().unwrap();
+            let parent = blockchain.genesis();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+            let header = Header { parent, nonce: 0, difficulty: blockchain.next_difficulty(&parent), timestamp: now, merkle_root: H256::default() };
+            (0..).map(|nonce| Header { nonce, ..header.clone() }).find(|h| h.hash() > h.difficulty).unwrap()
+        };
+        network.nodes[1].server.broadcast(Message::Headers(vec![header]));
+        assert!(network.wait_for(TIMEOUT, |n| n.nodes[0].server.peers().is_empty() && n.nodes[1].server.peers().is_empty()));
+
+        // the ban holds both ways
+        assert!(network.nodes[0].server.connect(node_addr(1)).is_err());
+        network.nodes[1].server.connect(node_addr(0)).unwrap();
+        assert!(network.wait_for(TIMEOUT, |n| n.nodes[1].server.peers().is_empty()));
+        assert!(network.nodes[0].server.peers().is_empty());
+    }
+
+    #[test]
+    fn miner_blocks_propagate() {
+        let network = Network::new(2, Params::default(), Config::default());
+        network.connect(0, 1);
+        network.nodes[0].miner.start(10_000);
+        assert!(network.wait_for(TIMEOUT, |n| n.nodes[1].height() >= 3 && n.nodes[0].miner.hash_rate() > 0));
+        network.nodes[0].miner.stop();
+        assert!(network.wait_for(TIMEOUT, |n| n.converged()));
+        assert_eq!(network.misbehavior(1), 0);
+    }
+
+    #[test]
+    fn miner_pauses_at_run_limit() {
+        let network = Network::new(1, Params::default(), Config::default());
+        let limits = miner::RunLimits { duration: None, count: Some(3) };
+        network.nodes[0].miner.start_limited(0, limits);
+        assert!(network.wait_for(TIMEOUT, |n| n.nodes[0].height() == 3));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(network.nodes[0].height(), 3);
+        // a new run starts counting again
+        network.nodes[0].miner.start_limited(0, limits);
+        assert!(network.wait_for(TIMEOUT, |n| n.nodes[0].height() == 6));
+        network.nodes[0].miner.pause();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(network.nodes[0].height(), 6);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::crypto::hash::H256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most blocks requested from one peer at a time
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// How many of the lowest missing blocks are considered for download, so that blocks arrive
+/// roughly in order instead of piling up as orphans
+const DOWNLOAD_WINDOW: usize = 1024;
+/// How long a peer has to deliver a block before it is asked of another peer
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Blocks of the header chain being downloaded, spread over the peers
+#[derive(Default)]
+pub struct BlockDownload {
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+}
+
+impl BlockDownload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pick the blocks to request from a peer among the missing blocks, lowest first, skipping
+    /// the ones other peers are still expected to deliver
+    pub fn assign(&mut self, peer: SocketAddr, missing: &[H256], now: Instant) -> Vec<H256> {
+        self.in_flight.retain(|_, (_, time)| now.duration_since(*time) < BLOCK_TIMEOUT);
+        let busy = self.in_flight.values().filter(|(p, _)| *p == peer).count();
+        let assigned: Vec<H256> = missing
+            .iter()
+            .take(DOWNLOAD_WINDOW)
+            .filter(|hash| !self.in_flight.contains_key(hash))
+            .take(MAX_BLOCKS_IN_FLIGHT.saturating_sub(busy))
+            .copied()
+            .collect();
+        for hash in assigned.iter() {
+            self.in_flight.insert(*hash, (peer, now));
+        }
+        assigned
+    }
+
+    /// Record that a block arrived, from whichever peer
+    pub fn received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(n: u8) -> Vec<H256> {
+        (1..=n).map(|i| H256::from([i; 32])).collect()
+    }
+
+    #[test]
+    fn spread_over_peers() {
+        let now = Instant::now();
+        let a = SocketAddr::from(([127, 0, 0, 1], 6001));
+        let b = SocketAddr::from(([127, 0, 0, 1], 6002));
+        let missing = hashes(40);
+        let mut download = BlockDownload::new();
+        assert_eq!(download.assign(a, &missing, now), missing[..16].to_vec());
+        // a peer with a full pipeline gets nothing more, another peer gets the next blocks
+        assert!(download.assign(a, &missing, now).is_empty());
+        assert_eq!(download.assign(b, &missing, now), missing[16..32].to_vec());
+        assert_eq!(download.in_flight(), 32);
+
+        download.received(&missing[0]);
+        download.received(&missing[1]);
+        assert_eq!(download.assign(a, &missing[2..], now), missing[32..34].to_vec());
+    }
+
+    #[test]
+    fn reassign_after_timeout() {
+        let now = Instant::now();
+        let a = SocketAddr::from(([127, 0, 0, 1], 6001));
+        let b = SocketAddr::from(([127, 0, 0, 1], 6002));
+        let missing = hashes(4);
+        let mut download = BlockDownload::new();
+        assert_eq!(download.assign(a, &missing, now).len(), 4);
+        assert!(download.assign(b, &missing, now + BLOCK_TIMEOUT / 2).is_empty());
+        assert_eq!(download.assign(b, &missing, now + BLOCK_TIMEOUT), missing);
+    }
+}
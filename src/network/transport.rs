@@ -0,0 +1,64 @@
+//! The connections the P2P server runs over. The server only sees these traits, so that the same
+//! handshake, framing and peer management can run over TCP or, in tests, over in-memory links.
+
+use mio::{self, net};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A connected, non-blocking byte stream, polled by the server's event loop
+pub trait Stream: Read + Write + mio::Evented + Send {
+    /// Another handle to the same connection, which stays open until every handle is dropped
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>>;
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+/// A non-blocking listener, readable when a connection is waiting to be accepted
+pub trait Listener: mio::Evented + Send {
+    fn accept(&self) -> std::io::Result<(Box<dyn Stream>, SocketAddr)>;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+pub trait Transport: Send + Sync {
+    fn bind(&self, addr: SocketAddr) -> std::io::Result<Box<dyn Listener>>;
+
+    /// Open a connection, blocking until it is established. Called off the event loop.
+    fn connect(&self, addr: SocketAddr, timeout: Duration) -> std::io::Result<Box<dyn Stream>>;
+}
+
+/// Connections over TCP
+pub struct Tcp;
+
+impl Transport for Tcp {
+    fn bind(&self, addr: SocketAddr) -> std::io::Result<Box<dyn Listener>> {
+        Ok(Box::new(net::TcpListener::bind(&addr)?))
+    }
+
+    fn connect(&self, addr: SocketAddr, timeout: Duration) -> std::io::Result<Box<dyn Stream>> {
+        let stream = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+        Ok(Box::new(net::TcpStream::from_stream(stream)?))
+    }
+}
+
+impl Stream for net::TcpStream {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>> {
+        Ok(Box::new(net::TcpStream::try_clone(self)?))
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        net::TcpStream::peer_addr(self)
+    }
+}
+
+impl Listener for net::TcpListener {
+    fn accept(&self) -> std::io::Result<(Box<dyn Stream>, SocketAddr)> {
+        let (stream, addr) = net::TcpListener::accept(self)?;
+        Ok((Box::new(stream), addr))
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        net::TcpListener::local_addr(self)
+    }
+}
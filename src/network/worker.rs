@@ -1,31 +1,116 @@
 use super::message::Message;
+use super::message;
 use super::peer;
+use super::orphan::OrphanPool;
+use super::compact::{CompactBlock, PartialBlock};
+use super::sync::BlockDownload;
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
 use log::{debug, warn, info};
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
 
 use std::thread;
 use std::sync::{Arc, Mutex};
-use crate::crypto::hash::{H256, H160, Hashable};
+use crate::crypto::hash::{H160, H256, Hashable};
 use crate::blockchain::Blockchain;
 use crate::block::Block;
-use crate::transaction::{self, Mempool, State, StateChain};
+use crate::transaction::{Mempool, StateChain};
+use crate::validation::{self, BlockValidationError};
 
 #[derive(Clone)]
 pub struct Context {
-    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_chan: channel::Receiver<(u8, Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
     statechain: Arc<Mutex<StateChain>>,
+    requests: Arc<Mutex<Requests>>,
+    download: Arc<Mutex<BlockDownload>>,
+    orphans: Arc<Mutex<OrphanPool>>,
+    partial_blocks: Arc<Mutex<PartialBlocks>>,
     self_address: H160,
 }
 
+/// How long a peer has to send the blocks or transactions we asked for before they count as
+/// unsolicited
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Blocks and transactions asked of each peer, to tell solicited data from unsolicited
+#[derive(Default)]
+struct Requests {
+    pending: HashMap<(SocketAddr, H256), Instant>,
+}
+
+impl Requests {
+    fn insert(&mut self, peer: SocketAddr, hashes: &[H256], now: Instant) {
+        self.pending.retain(|_, time| now.duration_since(*time) < REQUEST_TIMEOUT);
+        for hash in hashes {
+            self.pending.insert((peer, *hash), now);
+        }
+    }
+
+    /// Returns whether the hash was asked of the peer, and forgets the request
+    fn take(&mut self, peer: SocketAddr, hash: &H256) -> bool {
+        self.pending.remove(&(peer, *hash)).is_some()
+    }
+}
+
+/// Most compact blocks waiting for their missing transactions
+const MAX_PARTIAL_BLOCKS: usize = 16;
+
+/// Compact blocks waiting for the missing transactions asked of the peer that sent them
+#[derive(Default)]
+struct PartialBlocks {
+    pending: HashMap<H256, (SocketAddr, Instant, PartialBlock)>,
+}
+
+impl PartialBlocks {
+    /// Returns false if the block is already awaited or too many blocks are
+    fn insert(&mut self, peer: SocketAddr, partial: PartialBlock, now: Instant) -> bool {
+        self.pending.retain(|_, (_, time, _)| now.duration_since(*time) < REQUEST_TIMEOUT);
+        if self.pending.len() >= MAX_PARTIAL_BLOCKS || self.pending.contains_key(&partial.hash()) {
+            return false;
+        }
+        self.pending.insert(partial.hash(), (peer, now, partial));
+        true
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.pending.contains_key(hash)
+    }
+
+    /// Take the partial block if its missing transactions were asked of the peer
+    fn take(&mut self, peer: SocketAddr, hash: &H256) -> Option<PartialBlock> {
+        match self.pending.get(hash) {
+            Some((from, _, _)) if *from == peer => self.pending.remove(hash).map(|(_, _, partial)| partial),
+            _ => None,
+        }
+    }
+}
+
+/// Whether an inventory or data message carries nothing, which no honest peer sends
+fn is_empty(msg: &Message) -> bool {
+    match msg {
+        Message::NewBlockHashes(hashes)
+        | Message::GetBlocks(hashes)
+        | Message::NewTransactionHashes(hashes)
+        | Message::GetTransaction(hashes)
+        | Message::GetHeaders(hashes) => hashes.is_empty(),
+        Message::Headers(headers) => headers.is_empty(),
+        Message::Blocks(blocks) => blocks.is_empty(),
+        Message::Transactions(txs) => txs.is_empty(),
+        Message::GetBlockTransactions(_, indexes) => indexes.is_empty(),
+        Message::BlockTransactions(_, txs) => txs.is_empty(),
+        _ => false,
+    }
+}
+
 pub fn new(
     num_worker: usize,
-    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_src: channel::Receiver<(u8, Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
@@ -39,12 +124,21 @@ pub fn new(
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
         statechain: Arc::clone(statechain),
+        requests: Arc::new(Mutex::new(Requests::default())),
+        download: Arc::new(Mutex::new(BlockDownload::new())),
+        orphans: Arc::new(Mutex::new(OrphanPool::new())),
+        partial_blocks: Arc::new(Mutex::new(PartialBlocks::default())),
         self_address: self_address,
     }
 }
 
 
 impl Context {
+    /// Blocks received before their parent, waiting to be connected
+    pub fn orphans(&self) -> &Arc<Mutex<OrphanPool>> {
+        &self.orphans
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
@@ -56,14 +150,140 @@ impl Context {
         }
     }
 
+    /// Validate a block whose parent is in the blockchain against the state as of that parent,
+    /// then insert it and move the state to the new tip
+    fn process_block(&self, blockchain: &mut Blockchain, block: &Block) -> Result<(), BlockValidationError> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let mut statechain = self.statechain.lock().unwrap();
+        {
+            let parent_state = statechain.state_at(blockchain, &block.header.parent);
+            validation::validate_block(block, blockchain, &*parent_state, now)?;
+        }
+        // log info for receiving transaction value
+        for signed_tx in block.content.content.iter(){
+            for output in signed_tx.transaction.out_put.iter(){
+                if output.address != self.self_address{
+                    continue;
+                }
+                match signed_tx.sender() {
+                    Some(sender) => info!("{} receives {} value from {}", self.self_address, output.value, sender),
+                    None => info!("{} receives {} value from coinbase", self.self_address, output.value),
+                }
+            }
+        }
+        // now insert the received block into the blockchain, and move the state and mempool to the new tip
+        blockchain.insert(block);
+        let (disconnected, connected) = statechain.sync(blockchain);
+        self.mempool.lock().unwrap().sync(blockchain, &statechain.state, &disconnected, &connected);
+        Ok(())
+    }
+
+    /// Request the next blocks of the best header chain whose content is missing from a peer
+    fn request_missing_blocks(&self, blockchain: &Blockchain, peer: &peer::Handle) {
+        let now = Instant::now();
+        let hashes = self.download.lock().unwrap().assign(peer.addr(), &blockchain.missing_blocks(), now);
+        if !hashes.is_empty() {
+            debug!("Requesting {} blocks from {}", hashes.len(), peer.addr());
+            self.requests.lock().unwrap().insert(peer.addr(), &hashes, now);
+            peer.write(Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Forget the header of a block that failed validation, unless the failure may be ours
+    fn reject_block(&self, blockchain: &mut Blockchain, block: &Block, e: &BlockValidationError) -> Option<peer::Misbehavior> {
+        let misbehavior = peer::Misbehavior::from_block_error(e);
+        if misbehavior.is_some() {
+            blockchain.invalidate_header(&block.hash());
+        }
+        misbehavior
+    }
+
+    /// Insert a block whose parent is in the blockchain, along with the orphans waiting for it.
+    /// Returns the blocks connected, none if the block is invalid.
+    fn connect_block(&self, blockchain: &mut Blockchain, block: Block, peer: &peer::Handle) -> Vec<Block> {
+        if let Err(e) = self.process_block(blockchain, &block) {
+            warn!("Rejected block {}: {:?}", block.hash(), e);
+            if let Some(misbehavior) = self.reject_block(blockchain, &block, &e) {
+                self.server.misbehaving(peer, misbehavior);
+            }
+            return vec![];
+        }
+        // connect the orphans waiting for this block, and then for those
+        let mut connected = vec![];
+        let mut queue = vec![block];
+        while let Some(block) = queue.pop() {
+            let children = self.orphans.lock().unwrap().take_children(&block.hash());
+            for orphan_block in children {
+                if let Err(e) = self.process_block(blockchain, &orphan_block) {
+                    warn!("Rejected orphan block {}: {:?}", orphan_block.hash(), e);
+                    self.reject_block(blockchain, &orphan_block, &e);
+                    self.orphans.lock().unwrap().remove_descendants(&orphan_block.hash());
+                    continue;
+                }
+                queue.push(orphan_block);
+            }
+            connected.push(block);
+        }
+        connected
+    }
+
+    /// Connect a block whose parent is in the blockchain, along with the orphans waiting for it,
+    /// and return the hashes of the blocks connected for relaying
+    fn accept_block(&self, blockchain: &mut Blockchain, block: Block, peer: &peer::Handle) -> Vec<H256> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        self.connect_block(blockchain, block, peer)
+            .iter()
+            .map(|block| {
+                debug!(
+                    "Connected block {} of {} bytes, {} ms after it was mined",
+                    block.hash(),
+                    bincode::serialized_size(block).unwrap(),
+                    now.saturating_sub(block.header.timestamp)
+                );
+                block.hash()
+            })
+            .collect()
+    }
+
+    /// Announce newly connected blocks, sending the new tip as a compact block
+    fn relay_blocks(&self, blockchain: &Blockchain, hashes: Vec<H256>) {
+        let (tip, others): (Vec<H256>, Vec<H256>) = hashes.into_iter().partition(|hash| *hash == blockchain.tip_hash);
+        if !others.is_empty() {
+            self.server.broadcast(Message::NewBlockHashes(others));
+        }
+        if let Some(tip) = tip.first() {
+            let block = &blockchain.data[tip].block_content;
+            self.server.broadcast(Message::CompactBlock(CompactBlock::new(block, rand::random())));
+        }
+    }
+
+    /// Ask a peer for a full block, when it could not be rebuilt from a compact block
+    fn request_block(&self, hash: H256, peer: &peer::Handle) {
+        self.requests.lock().unwrap().insert(peer.addr(), &[hash], Instant::now());
+        peer.write(Message::GetBlocks(vec![hash]));
+    }
+
     fn worker_loop(&self) {
-        let mut orphan_buffer: Vec<Block> = Vec::new();
-        let mut delay_list: Vec<u128> = Vec::new();
         loop {
             // println!("{}", self.blockchain.lock().unwrap().tip_hash);
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg = match self.msg_chan.recv() {
+                Ok(msg) => msg,
+                // the server has shut down
+                Err(_) => return,
+            };
+            let (kind, msg, peer) = msg;
+            let msg = match peer::parse_message(kind, &msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Malformed message from peer {}: {}", peer.addr(), e);
+                    self.server.misbehaving(&peer, peer::Misbehavior::MalformedMessage);
+                    continue;
+                }
+            };
+            if is_empty(&msg) {
+                self.server.misbehaving(&peer, peer::Misbehavior::UnsolicitedData);
+                continue;
+            }
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -72,6 +292,25 @@ impl Context {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
+                Message::GetAddr => {
+                    let addrs = self.server.addrman().lock().unwrap().sample(message::MAX_ADDR, Instant::now());
+                    debug!("GetAddr: sharing {} addresses", addrs.len());
+                    if !addrs.is_empty() {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+                Message::Addr(addrs) => {
+                    debug!("Addr: {} addresses", addrs.len());
+                    let mut addrman = self.server.addrman().lock().unwrap();
+                    let now = Instant::now();
+                    for addr in addrs.into_iter().take(message::MAX_ADDR) {
+                        addrman.add(addr, now);
+                    }
+                }
+                Message::Version(_) | Message::Verack => {
+                    // the server completes the handshake before passing messages on
+                    debug!("Ignoring handshake message received after the handshake");
+                }
                 Message::NewBlockHashes(vec_hashes) => {
                     debug!("NewBlockHashes: {}", vec_hashes[0]);
                     let blockchain = self.blockchain.lock().unwrap();
@@ -83,8 +322,56 @@ impl Context {
                         ret_hashes.push(blockhash);
                     }
                     if ret_hashes.len() > 0 {
+                        self.requests.lock().unwrap().insert(peer.addr(), &ret_hashes, Instant::now());
                         peer.write(Message::GetBlocks(ret_hashes));
                     }
+                    // keep downloading, in case requests to a peer timed out
+                    self.request_missing_blocks(&blockchain, &peer);
+                }
+                Message::GetHeaders(locator) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let headers = blockchain.headers_after(&locator, message::MAX_HEADERS);
+                    debug!("GetHeaders: sending {} headers", headers.len());
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+                Message::Headers(headers) => {
+                    debug!("Headers: {} headers", headers.len());
+                    if headers.len() > message::MAX_HEADERS {
+                        self.server.misbehaving(&peer, peer::Misbehavior::UnsolicitedData);
+                        continue;
+                    }
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+                    let mut more = headers.len() == message::MAX_HEADERS;
+                    for header in headers.iter() {
+                        if blockchain.has_header(&header.hash()) {
+                            continue;
+                        }
+                        match validation::check_header(header, &blockchain, now) {
+                            Ok(()) => blockchain.insert_header(header),
+                            Err(BlockValidationError::UnknownParent(_)) => {
+                                // headers are missing in between, ask again from our best header
+                                more = true;
+                                break;
+                            }
+                            Err(e) => {
+                                warn!("Rejected header {}: {:?}", header.hash(), e);
+                                if let Some(misbehavior) = peer::Misbehavior::from_block_error(&e) {
+                                    self.server.misbehaving(&peer, misbehavior);
+                                }
+                                more = false;
+                                break;
+                            }
+                        }
+                    }
+                    if more {
+                        let last = headers.last().unwrap().hash();
+                        let from = if blockchain.has_header(&last) { last } else { blockchain.best_header() };
+                        peer.write(Message::GetHeaders(blockchain.locator(&from)));
+                    }
+                    self.request_missing_blocks(&blockchain, &peer);
                 }
                 Message::GetBlocks(vec_hashes) => {
                     debug!("GetBlocks: {}", vec_hashes[0]);
@@ -96,101 +383,153 @@ impl Context {
                         }
                         ret_blocks.push(blockchain.data.get(&blockhash).unwrap().block_content.clone());
                     }
-                    if ret_blocks.len() > 0 {
-                        peer.write(Message::Blocks(ret_blocks));
+                    for blocks in ret_blocks.chunks(message::MAX_BLOCKS) {
+                        peer.write(Message::Blocks(blocks.to_vec()));
                     }
                 }
                 Message::Blocks(vec_blocks) => {
                     debug!("Blocks: {}", "place_holder");
+                    let unsolicited = {
+                        let mut requests = self.requests.lock().unwrap();
+                        vec_blocks.iter().filter(|block| !requests.take(peer.addr(), &block.hash())).count()
+                    };
+                    if unsolicited > 0 {
+                        self.server.misbehaving(&peer, peer::Misbehavior::UnsolicitedData);
+                    }
                     let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut inv_hashes = Vec::new();
-                    let mut new_block_hashes = Vec::new();
-                    for block in vec_blocks.clone() {
-                        new_block_hashes.push(block.clone().hash());
-                        self.server.broadcast(Message::NewBlockHashes(new_block_hashes.clone()));
+                    {
+                        let mut download = self.download.lock().unwrap();
+                        for block in vec_blocks.iter() {
+                            download.received(&block.hash());
+                        }
                     }
+                    let mut inv_hashes = Vec::new();
                     for block in vec_blocks {
                         if blockchain.data.contains_key(&block.hash()){
                             continue;
                         }
-                        if blockchain.data.contains_key(&block.header.parent){
-                            if block.hash() <= block.header.difficulty && block.header.difficulty == 
-                                blockchain.data[&block.header.parent].block_content.header.difficulty{
-                                // before insert new block, first update corresponding state and statechain
-                                let mut statechain = self.statechain.lock().unwrap();
-                                let mut parent_state = State{data: statechain.data.get(&block.header.parent).unwrap().clone()};
-                                parent_state.update(block.clone().content.content);
-                                statechain.insert(block.hash(), parent_state);
-                                // log info for receiving transaction value  
-                                for signed_tx in block.clone().content.content{
-                                    for output in signed_tx.transaction.out_put{
-                                        if output.address != self.self_address{
-                                            continue;
-                                        }
-                                        info!("{} receives {} value from {}", self.self_address,
-                                            output.value, H160::from(H256::from(&signed_tx.pub_key[..])));
-                                    }
-                                }
-                                // now insert the received block into the blockchain
-                                blockchain.insert(&block);
-                                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
-				                delay_list.push(now - block.header.timestamp);
-        			            println!("Delays are {:?}", delay_list);
-                                let block_serialized: Vec<u8> = bincode::serialize(&block).unwrap();
-        			            println!("Block size is {}", block_serialized.len());
+                        if !blockchain.data.contains_key(&block.header.parent){
+                            let mut orphans = self.orphans.lock().unwrap();
+                            let now = Instant::now();
+                            orphans.expire(now);
+                            if !orphans.insert(&block, now) {
+                                continue;
                             }
-                            let mut new_block_list: Vec<Block> = Vec::new();
-	                        new_block_list.push(block.clone());
-                            while !new_block_list.is_empty(){
-	                            let mut new_block_list_future: Vec<Block> = Vec::new();
-                                let mut counter:usize = 0;
-	                            for orphan_block in orphan_buffer.clone(){
-	                                for new_block in new_block_list.clone(){
-	                                    if orphan_block.header.parent == new_block.hash() {
-                                            // before insert new block, first update corresponding state and statechain
-                                            let mut statechain = self.statechain.lock().unwrap();
-                                            let mut parent_state = State{data: statechain.data.get(&orphan_block.header.parent).unwrap().clone()};
-                                            parent_state.update(orphan_block.clone().content.content);
-                                            statechain.insert(orphan_block.hash(), parent_state);
-                                            // log info for receiving transaction value  
-                                            for signed_tx in block.clone().content.content{
-                                                for output in signed_tx.transaction.out_put{
-                                                    if output.address != self.self_address{
-                                                        continue;
-                                                    }
-                                                    info!("{} receives {} value from {}", self.self_address,
-                                                        output.value, H160::from(H256::from(&signed_tx.pub_key[..])));
-                                                }
-                                            }
-                                            // now insert the received block into the blockchain
-	                                        blockchain.insert(&orphan_block);
-                                            let block_serialized: Vec<u8> = bincode::serialize(&block).unwrap();
-        			                        println!("Block size is {}", block_serialized.len());
-                                            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
-				                            delay_list.push(now-block.header.timestamp);
-	                                        println!("Delays are {:?}", delay_list);
-						                    new_block_list_future.push(orphan_block);
-                                            orphan_buffer.remove(counter);
-                                            counter = counter - 1;
-                                            break;
-	                                    }
-	                                }
-                                    counter = counter + 1;
-	                            }
-	                            new_block_list = new_block_list_future;
+                            // ask the peer for the block the orphan is waiting for, unless it is
+                            // already being downloaded along the header chain
+                            let missing = orphans.missing_ancestor(&block.hash());
+                            if !blockchain.has_header(&missing) {
+                                debug!("Orphan block {}, requesting ancestor {}", block.hash(), missing);
+                                self.requests.lock().unwrap().insert(peer.addr(), &[missing], now);
+                                peer.write(Message::GetBlocks(vec![missing]));
+                                peer.write(Message::GetHeaders(blockchain.locator(&blockchain.best_header())));
                             }
+                            continue;
                         }
-                        else {
-                            orphan_buffer.push(block.clone());
+                        inv_hashes.extend(self.accept_block(&mut blockchain, block, &peer));
+                    }
+                    self.relay_blocks(&blockchain, inv_hashes);
+                    self.request_missing_blocks(&blockchain, &peer);
+                }
+                Message::CompactBlock(compact) => {
+                    let hash = compact.hash();
+                    debug!("CompactBlock: {} with {} short IDs", hash, compact.short_ids.len());
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    if blockchain.data.contains_key(&hash) || self.partial_blocks.lock().unwrap().contains(&hash) {
+                        continue;
+                    }
+                    if !blockchain.has_header(&hash) {
+                        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+                        match validation::check_header(&compact.header, &blockchain, now) {
+                            Ok(()) => blockchain.insert_header(&compact.header),
+                            Err(BlockValidationError::UnknownParent(_)) => {
+                                peer.write(Message::GetHeaders(blockchain.locator(&blockchain.best_header())));
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("Rejected compact block {}: {:?}", hash, e);
+                                if let Some(misbehavior) = peer::Misbehavior::from_block_error(&e) {
+                                    self.server.misbehaving(&peer, misbehavior);
+                                }
+                                continue;
+                            }
                         }
-                        if blockchain.tip_hash == Hashable::hash(&block){
-                            inv_hashes.push(blockchain.tip_hash);
+                    }
+                    if !blockchain.data.contains_key(&compact.header.parent) {
+                        // the block is fetched in full along the header chain
+                        self.request_missing_blocks(&blockchain, &peer);
+                        continue;
+                    }
+                    let partial = match PartialBlock::reconstruct(&compact, &self.mempool.lock().unwrap()) {
+                        Some(partial) => partial,
+                        None => {
+                            self.server.misbehaving(&peer, peer::Misbehavior::MalformedMessage);
+                            continue;
+                        }
+                    };
+                    let missing = partial.missing();
+                    if !missing.is_empty() {
+                        debug!("Compact block {} misses {} transactions", hash, missing.len());
+                        if self.partial_blocks.lock().unwrap().insert(peer.addr(), partial, Instant::now()) {
+                            peer.write(Message::GetBlockTransactions(hash, missing));
+                        } else {
+                            self.request_block(hash, &peer);
                         }
+                        continue;
                     }
-                    if inv_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(inv_hashes));
+                    let block = match partial.into_block() {
+                        Some(block) => block,
+                        None => {
+                            debug!("Compact block {} matched the wrong transactions", hash);
+                            self.request_block(hash, &peer);
+                            continue;
+                        }
+                    };
+                    self.download.lock().unwrap().received(&hash);
+                    let inv_hashes = self.accept_block(&mut blockchain, block, &peer);
+                    self.relay_blocks(&blockchain, inv_hashes);
+                }
+                Message::GetBlockTransactions(hash, indexes) => {
+                    debug!("GetBlockTransactions: {} transactions of {}", indexes.len(), hash);
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let content = match blockchain.data.get(&hash) {
+                        Some(block) => &block.block_content.content.content,
+                        None => continue,
+                    };
+                    let txs: Option<Vec<_>> = indexes.iter().map(|i| content.get(*i as usize).cloned()).collect();
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTransactions(hash, txs)),
+                        None => self.server.misbehaving(&peer, peer::Misbehavior::MalformedMessage),
                     }
-
+                }
+                Message::BlockTransactions(hash, txs) => {
+                    debug!("BlockTransactions: {} transactions of {}", txs.len(), hash);
+                    let mut partial = match self.partial_blocks.lock().unwrap().take(peer.addr(), &hash) {
+                        Some(partial) => partial,
+                        None => {
+                            self.server.misbehaving(&peer, peer::Misbehavior::UnsolicitedData);
+                            continue;
+                        }
+                    };
+                    if !partial.fill(txs) {
+                        self.server.misbehaving(&peer, peer::Misbehavior::MalformedMessage);
+                        continue;
+                    }
+                    let block = match partial.into_block() {
+                        Some(block) => block,
+                        None => {
+                            debug!("Compact block {} matched the wrong transactions", hash);
+                            self.request_block(hash, &peer);
+                            continue;
+                        }
+                    };
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    if blockchain.data.contains_key(&hash) || !blockchain.data.contains_key(&block.header.parent) {
+                        continue;
+                    }
+                    self.download.lock().unwrap().received(&hash);
+                    let inv_hashes = self.accept_block(&mut blockchain, block, &peer);
+                    self.relay_blocks(&blockchain, inv_hashes);
                 }
                 Message::NewTransactionHashes(vec_hashes) => {
                     let mempool = self.mempool.lock().unwrap();
@@ -203,6 +542,7 @@ impl Context {
                     }
                     if ret_hashes.len() > 0 {
                         debug!("NewTransactionHashes: {}, Mempool Size {}", vec_hashes.clone()[0], mempool.total_size);
+                        self.requests.lock().unwrap().insert(peer.addr(), &ret_hashes, Instant::now());
                         peer.write(Message::GetTransaction(ret_hashes));
                     }
                 }
@@ -213,7 +553,7 @@ impl Context {
                         if mempool.data.contains_key(&tx_hash) == false{
                             continue;
                         }
-                        ret_txs.push(mempool.data.get(&tx_hash).unwrap().clone());
+                        ret_txs.push(mempool.data.get(&tx_hash).unwrap().transaction.clone());
                     }
                     if ret_txs.len() > 0 {
                         debug!("GetTransaction: {}, Mempool Size {}", vec_hashes.clone()[0], mempool.total_size);
@@ -223,41 +563,47 @@ impl Context {
                 Message::Transactions(vec_txs) => {
                     debug!("Transactions: {}", "place_holder");
                     let mut inv_hashes = Vec::new();
-                    let mut inv_tx = Vec::new();
+                    let unsolicited = {
+                        let mut requests = self.requests.lock().unwrap();
+                        vec_txs.iter().filter(|tx| !requests.take(peer.addr(), &tx.hash())).count()
+                    };
+                    if unsolicited > 0 {
+                        self.server.misbehaving(&peer, peer::Misbehavior::UnsolicitedData);
+                    }
 
-                    let current_tip_hash = self.blockchain.lock().unwrap().tip_hash;
-                    let state = self.statechain.lock().unwrap().data.get(& current_tip_hash).unwrap().clone();
+                    // lock order is blockchain before statechain, as in the miner
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let statechain = self.statechain.lock().unwrap();
+                    let mut mempool = self.mempool.lock().unwrap();
+                    let height = blockchain.tip_height + 1;
+                    mempool.expire(Instant::now());
 
                     for tx in vec_txs {
-                        if self.mempool.lock().unwrap().data.contains_key(&tx.hash()){
-                            continue;
-                        }
-                        // check if the transaction is signed correctly
-                        if transaction::verify(&tx.transaction, tx.clone().pub_key, 
-                            tx.clone().signature) == false{
-                            println!("Transaction sign verification failed");
+                        if mempool.data.contains_key(&tx.hash()){
                             continue;
                         }
-                        // check if double spend error may occur
-                        if state.contains_key(
-                            &(tx.transaction.in_put[0].tx_hash, tx.transaction.in_put[0].index)) == false{
-                            println!("Double spend checking failed");
-                            continue;
-                        }
-                        // check if the input of tx is the person who make the tx
-                        let (_value, addr) = state.get(
-                            &(tx.transaction.in_put[0].tx_hash, tx.transaction.in_put[0].index)).unwrap().clone();
-                        if ( addr == H160::from(H256::from(&tx.pub_key[..])) ) == false{
-                            println!("Same sender input owner check failed");
+                        // the transaction may spend outputs of unconfirmed transactions
+                        let fee = match validation::check_transaction(&tx, &mempool.view(&statechain.state), height, &blockchain.params) {
+                            Ok(fee) => fee,
+                            Err(e) => {
+                                debug!("Rejected transaction {}: {:?}", tx.hash(), e);
+                                if let Some(misbehavior) = peer::Misbehavior::from_transaction_error(&e) {
+                                    self.server.misbehaving(&peer, misbehavior);
+                                }
+                                continue;
+                            }
+                        };
+                        let size = bincode::serialized_size(&tx).unwrap() as usize;
+                        if fee < mempool.min_fee(size) {
+                            debug!("Transaction {} pays {} for {} bytes, below the minimum relay fee", tx.hash(), fee, size);
                             continue;
                         }
+                        mempool.insert(&tx, fee);
                         inv_hashes.push(tx.hash());
-                        inv_tx.push(tx.clone());
                     }
                     if inv_hashes.len() > 0 {
-                        self.mempool.lock().unwrap().insert_vec(inv_tx);
                         self.server.broadcast(Message::NewTransactionHashes(inv_hashes));
-                        debug!("After include new TX, Mempool size is {}", self.mempool.lock().unwrap().total_size);
+                        debug!("After include new TX, Mempool size is {}", mempool.total_size);
                     }
                 }
             }
@@ -0,0 +1,229 @@
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// File holding the serialized blocks, each prefixed by its 4-byte big-endian length
+const BLOCK_FILE: &str = "blocks.dat";
+/// File holding one fixed-size index record per block in `BLOCK_FILE`
+const INDEX_FILE: &str = "blocks.idx";
+/// Index record layout: hash (32) | height (4) | offset (8) | length (4)
+const INDEX_RECORD_SIZE: usize = 48;
+
+/// A storage backend that the blockchain writes every new block through to, so that a restarted
+/// node can reload its chain instead of syncing it again from peers.
+pub trait BlockStore: Send {
+    /// Persist a block at the given height. Storing a block that is already present is a no-op.
+    fn put(&mut self, block: &Block, height: u32) -> io::Result<()>;
+
+    /// Read back all stored blocks in the order they were put, so parents precede children.
+    fn load(&mut self) -> io::Result<Vec<Block>>;
+}
+
+/// A store that keeps nothing, used when the node runs without a data directory.
+#[derive(Default)]
+pub struct MemoryStore;
+
+impl BlockStore for MemoryStore {
+    fn put(&mut self, _block: &Block, _height: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        Ok(Vec::new())
+    }
+}
+
+struct IndexEntry {
+    height: u32,
+    offset: u64,
+    length: u32,
+}
+
+/// An append-only block file plus a hash/height index, both kept in one data directory.
+pub struct FileStore {
+    blocks: File,
+    index: File,
+    entries: HashMap<H256, IndexEntry>,
+    /// Hashes in the order they were appended
+    order: Vec<H256>,
+    /// Length of the valid part of the block file
+    blocks_len: u64,
+}
+
+impl FileStore {
+    /// Open the store in `dir`, creating the directory and files if needed. A block file that
+    /// was appended to without the index being updated (e.g. after a crash) is re-indexed, and
+    /// a trailing partial record is discarded.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let blocks = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(dir.join(BLOCK_FILE))?;
+        let index = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(dir.join(INDEX_FILE))?;
+        let mut store = FileStore{blocks, index, entries: HashMap::new(), order: Vec::new(), blocks_len: 0};
+        store.read_index()?;
+        store.recover_tail()?;
+        Ok(store)
+    }
+
+    /// Number of blocks in the store
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Height the block was stored at, if present
+    pub fn height(&self, hash: &H256) -> Option<u32> {
+        self.entries.get(hash).map(|e| e.height)
+    }
+
+    fn read_index(&mut self) -> io::Result<()> {
+        let blocks_file_len = self.blocks.metadata()?.len();
+        let mut raw = Vec::new();
+        self.index.seek(SeekFrom::Start(0))?;
+        self.index.read_to_end(&mut raw)?;
+        for record in raw.chunks_exact(INDEX_RECORD_SIZE) {
+            let hash = H256::from(&record[0..32]);
+            let height = u32::from_be_bytes(record[32..36].try_into().unwrap());
+            let offset = u64::from_be_bytes(record[36..44].try_into().unwrap());
+            let length = u32::from_be_bytes(record[44..48].try_into().unwrap());
+            if offset + 4 + length as u64 > blocks_file_len {
+                warn!("Block index points past the end of the block file, dropping the rest");
+                break;
+            }
+            self.blocks_len = offset + 4 + length as u64;
+            self.entries.insert(hash, IndexEntry{height, offset, length});
+            self.order.push(hash);
+        }
+        // cut off any partial or dangling index record
+        self.index.set_len((self.order.len() * INDEX_RECORD_SIZE) as u64)?;
+        Ok(())
+    }
+
+    fn recover_tail(&mut self) -> io::Result<()> {
+        let file_len = self.blocks.metadata()?.len();
+        let mut offset = self.blocks_len;
+        while offset + 4 <= file_len {
+            let mut len_buffer = [0u8; 4];
+            self.blocks.seek(SeekFrom::Start(offset))?;
+            self.blocks.read_exact(&mut len_buffer)?;
+            let length = u32::from_be_bytes(len_buffer);
+            if offset + 4 + length as u64 > file_len {
+                break;
+            }
+            let block = match self.read_at(offset, length) {
+                Ok(block) => block,
+                Err(_) => break,
+            };
+            let height = match self.entries.get(&block.header.parent) {
+                Some(parent) => parent.height + 1,
+                None => 1,
+            };
+            warn!("Re-indexing block {} found after the end of the block index", block.hash());
+            self.write_index(&block.hash(), height, offset, length)?;
+            offset += 4 + length as u64;
+            self.blocks_len = offset;
+        }
+        // drop a trailing partial block, if any
+        self.blocks.set_len(self.blocks_len)?;
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, length: u32) -> io::Result<Block> {
+        let mut buffer = vec![0u8; length as usize];
+        self.blocks.seek(SeekFrom::Start(offset + 4))?;
+        self.blocks.read_exact(&mut buffer)?;
+        bincode::deserialize(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_index(&mut self, hash: &H256, height: u32, offset: u64, length: u32) -> io::Result<()> {
+        let mut record = Vec::with_capacity(INDEX_RECORD_SIZE);
+        record.extend_from_slice(hash.as_ref());
+        record.extend_from_slice(&height.to_be_bytes());
+        record.extend_from_slice(&offset.to_be_bytes());
+        record.extend_from_slice(&length.to_be_bytes());
+        self.index.seek(SeekFrom::Start((self.order.len() * INDEX_RECORD_SIZE) as u64))?;
+        self.index.write_all(&record)?;
+        self.entries.insert(*hash, IndexEntry{height, offset, length});
+        self.order.push(*hash);
+        Ok(())
+    }
+}
+
+impl BlockStore for FileStore {
+    fn put(&mut self, block: &Block, height: u32) -> io::Result<()> {
+        let hash = block.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(());
+        }
+        let serialized = bincode::serialize(block).unwrap();
+        let offset = self.blocks_len;
+        // the block goes to disk before its index record, so a crash in between is recoverable
+        self.blocks.seek(SeekFrom::Start(offset))?;
+        self.blocks.write_all(&(serialized.len() as u32).to_be_bytes())?;
+        self.blocks.write_all(&serialized)?;
+        self.blocks.sync_data()?;
+        self.blocks_len = offset + 4 + serialized.len() as u64;
+        self.write_index(&hash, height, offset, serialized.len() as u32)
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        let mut blocks = Vec::with_capacity(self.order.len());
+        for hash in self.order.clone() {
+            let (offset, length) = {
+                let entry = &self.entries[&hash];
+                (entry.offset, entry.length)
+            };
+            blocks.push(self.read_at(offset, length)?);
+        }
+        Ok(blocks)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use std::path::PathBuf;
+
+    /// A fresh, empty directory under the system temp dir
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let nonce: u64 = rand::random();
+        let dir = std::env::temp_dir().join(format!("bitcoin-{}-{:x}", name, nonce));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn recover_unindexed_tail() {
+        let dir = temp_dir("store-tail");
+        let parent = generate_random_block(&H256::from([0; 32]));
+        let child = generate_random_block(&parent.hash());
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.put(&parent, 1).unwrap();
+            store.put(&child, 2).unwrap();
+        }
+        // lose the last index record and leave half a block behind
+        let index = OpenOptions::new().write(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.set_len(INDEX_RECORD_SIZE as u64).unwrap();
+        let mut blocks = OpenOptions::new().append(true).open(dir.join(BLOCK_FILE)).unwrap();
+        blocks.write_all(&[0, 0, 1, 0, 42]).unwrap();
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].hash(), child.hash());
+        assert_eq!(store.height(&child.hash()), Some(2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
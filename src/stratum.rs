@@ -0,0 +1,255 @@
+//! A minimal line-based JSON-RPC mining protocol in the style of Stratum, which lets external
+//! hashers mine on the node's block templates.
+//!
+//! Every message is a JSON object on its own line. A client sends `mining.subscribe`, and is
+//! then sent a `mining.notify` job `[job_id, height, parent, merkle_root, difficulty, timestamp]`
+//! whenever the tip moves. Once the header with some nonce and timestamp hashes under the
+//! difficulty, the client sends `mining.submit` `[job_id, nonce, timestamp]`.
+
+use crate::crypto::hash::Hashable;
+use crate::miner::{Assembler, BlockTemplate};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// How often a subscribed connection checks for a new tip
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Jobs a connection keeps, so that a solution to a recent job is still accepted
+const MAX_JOBS: usize = 4;
+/// Longest request line accepted
+const MAX_LINE: usize = 4096;
+
+#[derive(Deserialize)]
+struct Request {
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    id: Value,
+    result: Value,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Notification {
+    id: Value,
+    method: &'static str,
+    params: Value,
+}
+
+/// Accept hashers on `addr`, each on its own thread. Returns the address bound to.
+pub fn start(addr: SocketAddr, assembler: &Assembler) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let assembler = assembler.clone();
+    thread::Builder::new()
+        .name("stratum".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Error accepting stratum connection: {}", e);
+                        continue;
+                    }
+                };
+                let session = Session { assembler: assembler.clone(), subscribed: false, next_job: 0, jobs: VecDeque::new() };
+                thread::spawn(move || {
+                    let peer_addr = stream.peer_addr();
+                    if let Err(e) = session.run(stream) {
+                        debug!("Stratum connection {:?} closed: {}", peer_addr, e);
+                    }
+                });
+            }
+        })?;
+    info!("Stratum server listening at {}", local_addr);
+    Ok(local_addr)
+}
+
+struct Session {
+    assembler: Assembler,
+    subscribed: bool,
+    next_job: u64,
+    /// The latest jobs sent, oldest first
+    jobs: VecDeque<(u64, BlockTemplate)>,
+}
+
+impl Session {
+    fn run(mut self, stream: TcpStream) -> io::Result<()> {
+        // time out reads to notice new tips while the client is quiet
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        // a line read in several parts keeps what was read before a timeout
+        let mut line = Vec::new();
+        loop {
+            // read no further than one byte past the limit, so a line with no end is cut off
+            let limit = (MAX_LINE + 1 - line.len()) as u64;
+            match (&mut reader).take(limit).read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) if line.ends_with(b"\n") => {
+                    let response = self.handle(&line);
+                    writeln!(writer, "{}", serde_json::to_string(&response).unwrap())?;
+                    line.clear();
+                }
+                Ok(_) if line.len() > MAX_LINE => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            let tip = self.assembler.tip();
+            if self.subscribed && self.jobs.back().is_none_or(|(_, template)| template.header.parent != tip) {
+                let notification = self.new_job();
+                writeln!(writer, "{}", serde_json::to_string(&notification).unwrap())?;
+            }
+        }
+    }
+
+    fn handle(&mut self, line: &[u8]) -> Response {
+        let request: Request = match serde_json::from_slice(line) {
+            Ok(request) => request,
+            Err(e) => return Response { id: Value::Null, result: Value::Null, error: Some(format!("parse error: {}", e)) },
+        };
+        let result = match request.method.as_str() {
+            "mining.subscribe" => {
+                self.subscribed = true;
+                Ok(Value::Bool(true))
+            }
+            "mining.submit" => self.submit(&request.params).map(|()| Value::Bool(true)),
+            method => Err(format!("unknown method {}", method)),
+        };
+        match result {
+            Ok(result) => Response { id: request.id, result, error: None },
+            Err(e) => Response { id: request.id, result: Value::Null, error: Some(e) },
+        }
+    }
+
+    /// Make a template on the tip and announce it as a new job
+    fn new_job(&mut self) -> Notification {
+        let template = self.assembler.template();
+        self.next_job += 1;
+        let header = &template.header;
+        let params = json!([
+            self.next_job.to_string(),
+            template.height,
+            header.parent.to_string(),
+            header.merkle_root.to_string(),
+            header.difficulty.to_string(),
+            header.timestamp as u64,
+        ]);
+        self.jobs.push_back((self.next_job, template));
+        if self.jobs.len() > MAX_JOBS {
+            self.jobs.pop_front();
+        }
+        Notification { id: Value::Null, method: "mining.notify", params }
+    }
+
+    fn submit(&mut self, params: &[Value]) -> Result<(), String> {
+        let (job_id, nonce, timestamp) = match params {
+            [job_id, nonce, timestamp] => (
+                job_id.as_str().and_then(|id| id.parse::<u64>().ok()),
+                nonce.as_u64().and_then(|nonce| u32::try_from(nonce).ok()),
+                timestamp.as_u64(),
+            ),
+            _ => return Err("expected [job_id, nonce, timestamp]".to_string()),
+        };
+        let (job_id, nonce, timestamp) = match (job_id, nonce, timestamp) {
+            (Some(job_id), Some(nonce), Some(timestamp)) => (job_id, nonce, timestamp),
+            _ => return Err("expected [job_id, nonce, timestamp]".to_string()),
+        };
+        let template = match self.jobs.iter().find(|(id, _)| *id == job_id) {
+            Some((_, template)) => template,
+            None => return Err(format!("unknown or stale job {}", job_id)),
+        };
+        let block = template.block(nonce, u128::from(timestamp));
+        self.assembler.submit(&block).map_err(|e| format!("rejected: {:?}", e))?;
+        info!("Stratum client mined block {}", block.hash());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Header;
+    use crate::consensus::Params;
+    use crate::network::sim::{Config, Network};
+
+    fn read(reader: &mut BufReader<TcpStream>) -> Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn client_mines_blocks() {
+        let network = Network::new(1, Params::default(), Config::default());
+        let node = &network.nodes[0];
+        let addr = start("127.0.0.1:0".parse().unwrap(), node.miner.assembler()).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        writeln!(writer, "not json").unwrap();
+        assert!(read(&mut reader)["error"].is_string());
+        writeln!(writer, "{}", json!({"id": 1, "method": "mining.subscribe", "params": []})).unwrap();
+        assert_eq!(read(&mut reader), json!({"id": 1, "result": true, "error": null}));
+
+        let mut submit = Value::Null;
+        for height in 1..=2 {
+            let job = read(&mut reader);
+            assert_eq!(job["method"], "mining.notify");
+            let params = &job["params"];
+            assert_eq!(params[1], height);
+            let parse = |i: usize| params[i].as_str().unwrap().parse().unwrap();
+            let header = Header {
+                parent: parse(2),
+                nonce: 0,
+                difficulty: parse(4),
+                timestamp: u128::from(params[5].as_u64().unwrap()),
+                merkle_root: parse(3),
+            };
+            let nonce = (0..).find(|&nonce| Header { nonce, ..header.clone() }.hash() <= header.difficulty).unwrap();
+            submit = json!({"id": 2, "method": "mining.submit", "params": [params[0], nonce, params[5]]});
+            writeln!(writer, "{}", submit).unwrap();
+            assert_eq!(read(&mut reader)["result"], true);
+            assert_eq!(node.height(), height);
+        }
+
+        // the next job is sent as soon as the block connects, and a block is only taken once
+        assert_eq!(read(&mut reader)["params"][1], 3);
+        writeln!(writer, "{}", submit).unwrap();
+        assert!(read(&mut reader)["error"].is_string());
+        assert_eq!(node.height(), 2);
+    }
+
+    #[test]
+    fn long_line_closes_connection() {
+        let network = Network::new(1, Params::default(), Config::default());
+        let addr = start("127.0.0.1:0".parse().unwrap(), network.nodes[0].miner.assembler()).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        // keep sending without a newline, never pausing long enough for a read to time out
+        let mut writer = stream.try_clone().unwrap();
+        thread::spawn(move || while writer.write_all(&[b'a'; 64]).is_ok() {});
+        let mut rest = Vec::new();
+        match stream.read_to_end(&mut rest) {
+            Ok(_) => assert!(rest.is_empty()),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        }
+    }
+}
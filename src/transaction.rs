@@ -1,7 +1,15 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{self, Ed25519KeyPair, KeyPair};
 use crate::crypto::hash::{H256, H160, Hashable};
-use std::collections::HashMap;
+use crate::blockchain::Blockchain;
+use crate::block::Block;
+use crate::consensus::Params;
+use crate::validation;
+use log::{debug, info};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, Hash)]
 pub struct Transaction {
@@ -38,6 +46,25 @@ impl Hashable for SignedTransaction {
     }
 }
 
+impl SignedTransaction {
+    /// Create the coinbase transaction of a block at `height`, paying `value` to `address`. The
+    /// height goes into the input's index, so coinbases of different blocks never share a hash.
+    pub fn coinbase(height: u32, address: H160, value: u64) -> Self {
+        let input = Input{tx_hash: H256::from([0; 32]), index: height as usize, coin_base: true};
+        let output = Output{address, value};
+        SignedTransaction{transaction: Transaction{in_put: vec![input], out_put: vec![output]},
+                          signature: Vec::new(), pub_key: Vec::new()}
+    }
+
+    /// Address of the signer, or `None` for a coinbase, which is not signed
+    pub fn sender(&self) -> Option<H160> {
+        if self.transaction.is_coinbase() || self.pub_key.len() < 32 {
+            return None;
+        }
+        Some(H160::from(H256::from(&self.pub_key[..])))
+    }
+}
+
 
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Vec<u8> {
@@ -54,85 +81,609 @@ pub fn verify(t: &Transaction, public_key: Vec<u8>, signature: Vec<u8>) -> bool
 }
 
 
+/// Local rules on which transactions the mempool admits and how long it keeps them. Unlike
+/// consensus parameters, these may differ between nodes.
+#[derive(Debug, Clone)]
+pub struct MempoolPolicy {
+    /// Minimum fee per 1000 bytes for a transaction to be relayed
+    pub min_relay_fee: u64,
+    /// Most serialized bytes of transactions kept before the lowest fee rates are evicted
+    pub max_bytes: usize,
+    /// Most transactions kept before the lowest fee rates are evicted
+    pub max_count: usize,
+    /// How long a transaction may wait for confirmation before it is dropped
+    pub expiry: Duration,
+    /// Whether a transaction may replace the entries it conflicts with by paying more
+    pub replace_by_fee: bool,
+}
+
+impl Default for MempoolPolicy {
+    fn default() -> Self {
+        MempoolPolicy {
+            min_relay_fee: 10,
+            max_bytes: 32_000_000,
+            max_count: 100_000,
+            expiry: Duration::from_secs(60 * 60),
+            replace_by_fee: false,
+        }
+    }
+}
+
+/// Most entries, descendants included, that a single replacement may evict
+const MAX_REPLACED: usize = 100;
+
+/// Fee paid per 1000 bytes of serialized transaction
+pub fn fee_rate(fee: u64, size: usize) -> u64 {
+    fee.saturating_mul(1000) / cmp::max(size, 1) as u64
+}
+
+/// A transaction waiting in the mempool, with the fee it pays, its serialized size, when it
+/// was admitted, and the other entries it spends from or that spend from it
+#[derive(Clone)]
+pub struct MempoolEntry {
+    pub transaction: SignedTransaction,
+    pub fee: u64,
+    pub size: usize,
+    pub time: Instant,
+    pub parents: HashSet<H256>,
+    pub children: HashSet<H256>,
+    /// Totals of the entry together with its unconfirmed ancestors, kept up to date as entries
+    /// come and go so that block templates don't walk every entry's ancestors
+    pub ancestor_fee: u64,
+    pub ancestor_size: usize,
+    pub ancestor_count: usize,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+/// Number and size of the mempool transactions whose fee rate is at least `min_fee_rate`, and
+/// below the next bucket's
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FeeRateBucket {
+    pub min_fee_rate: u64,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MempoolStats {
+    pub count: usize,
+    pub bytes: usize,
+    pub total_fee: u64,
+    /// Non-empty buckets in increasing order, with bounds 0, 1, 2, 4, 8, ...
+    pub fee_rate_histogram: Vec<FeeRateBucket>,
+}
+
 #[derive(Clone)]
 pub struct Mempool {
-    pub data: HashMap<H256, SignedTransaction>,
+    pub data: HashMap<H256, MempoolEntry>,
+    /// Entries ordered by fee rate, ties broken by hash
+    by_fee_rate: BTreeSet<(u64, H256)>,
+    /// Entries ordered by admission time, for expiry
+    by_time: BTreeSet<(Instant, H256)>,
+    /// The mempool transaction spending each outpoint, so that no two entries conflict
+    spends: HashMap<Outpoint, H256>,
+    /// Number of transactions
     pub total_size: u32,
+    /// Serialized size of all transactions
+    pub total_bytes: usize,
+    pub policy: MempoolPolicy,
 }
 
 impl Mempool{
     pub fn new() -> Self {
+        Self::with_policy(MempoolPolicy::default())
+    }
+
+    pub fn with_policy(policy: MempoolPolicy) -> Self {
         let data_new = HashMap::new();
-        Mempool{data: data_new, total_size: 0}
+        Mempool{data: data_new, by_fee_rate: BTreeSet::new(), by_time: BTreeSet::new(), spends: HashMap::new(),
+                total_size: 0, total_bytes: 0, policy}
+    }
+
+    /// Smallest fee the relay policy accepts for a transaction of `size` bytes
+    pub fn min_fee(&self, size: usize) -> u64 {
+        let fee = self.policy.min_relay_fee as u128 * size as u128;
+        fee.div_ceil(1000) as u64
     }
 
-    pub fn insert(&mut self, transaction: &SignedTransaction) -> bool{
-        if self.data.contains_key(&Hashable::hash(transaction)){
+    /// A view of `state` that also contains the outputs of every mempool transaction, to check
+    /// transactions that spend unconfirmed outputs against
+    pub fn view<'a>(&'a self, state: &'a dyn UtxoView) -> MempoolView<'a> {
+        MempoolView{mempool: self, base: state}
+    }
+
+    /// Add a transaction that pays `fee`. Returns false if it is already in the mempool, if it
+    /// conflicts with an entry by spending the same output and may not replace it, or if the
+    /// mempool is full of transactions paying a higher fee rate.
+    pub fn insert(&mut self, transaction: &SignedTransaction, fee: u64) -> bool{
+        let hash = Hashable::hash(transaction);
+        if self.data.contains_key(&hash){
             return false
         }
-        self.data.insert(Hashable::hash(transaction), transaction.clone());
+        let size = bincode::serialized_size(transaction).unwrap() as usize;
+        let conflicts = self.conflicts(transaction);
+        if !conflicts.is_empty() {
+            if let Err(reason) = self.check_replacement(transaction, fee, size, &conflicts) {
+                debug!("Transaction {} conflicts with the mempool: {}", hash, reason);
+                return false;
+            }
+            for conflict in conflicts.iter() {
+                debug!("Transaction {} replaces {}", hash, conflict);
+                self.remove_with_descendants(conflict);
+            }
+        }
+        let spent = spent_outpoints(&transaction.transaction);
+        let parents: HashSet<H256> = spent.iter().map(|outpoint| outpoint.0).filter(|h| self.data.contains_key(h)).collect();
+        // entries can already spend this transaction's outputs when it comes back after a reorg
+        let children: HashSet<H256> = (0..transaction.transaction.out_put.len())
+            .filter_map(|i| self.spends.get(&(hash, i)).copied())
+            .collect();
+        for parent in parents.iter() {
+            self.data.get_mut(parent).unwrap().children.insert(hash);
+        }
+        for child in children.iter() {
+            self.data.get_mut(child).unwrap().parents.insert(hash);
+        }
+        for outpoint in spent {
+            self.spends.insert(outpoint, hash);
+        }
+        let entry = MempoolEntry{transaction: transaction.clone(), fee, size, time: Instant::now(), parents, children,
+                                 ancestor_fee: fee, ancestor_size: size, ancestor_count: 1};
+        self.by_fee_rate.insert((entry.fee_rate(), hash));
+        self.by_time.insert((entry.time, hash));
+        self.data.insert(hash, entry);
+        let mut changed = self.descendants(&hash);
+        changed.insert(hash);
+        self.update_ancestor_totals(&changed);
         self.total_size += 1;
-        return true
+        self.total_bytes += size;
+        self.trim();
+        self.data.contains_key(&hash)
     }
 
-    pub fn insert_vec(&mut self, transaction_vec: Vec<SignedTransaction>) {
-        for tx in transaction_vec{
-            self.insert(&tx);
+    /// Whether `transaction` may replace the entries it conflicts with: it must pay a higher fee
+    /// rate than each of them, and a fee covering everything it evicts plus its own relay fee
+    fn check_replacement(&self, transaction: &SignedTransaction, fee: u64, size: usize, conflicts: &[H256]) -> Result<(), &'static str> {
+        if !self.policy.replace_by_fee {
+            return Err("replace-by-fee is disabled");
         }
+        let mut replaced: HashSet<H256> = HashSet::new();
+        for conflict in conflicts.iter() {
+            replaced.insert(*conflict);
+            replaced.extend(self.descendants(conflict));
+        }
+        if replaced.len() > MAX_REPLACED {
+            return Err("too many transactions would be replaced");
+        }
+        if spent_outpoints(&transaction.transaction).iter().any(|outpoint| replaced.contains(&outpoint.0)) {
+            return Err("spends an output of a transaction it replaces");
+        }
+        if conflicts.iter().any(|conflict| self.data[conflict].fee_rate() >= fee_rate(fee, size)) {
+            return Err("fee rate is not higher than the replaced transaction's");
+        }
+        let replaced_fee = replaced.iter().map(|h| self.data[h].fee).fold(0, u64::saturating_add);
+        if fee < replaced_fee.saturating_add(self.min_fee(size)) {
+            return Err("fee does not cover the replaced transactions");
+        }
+        Ok(())
     }
 
-    pub fn retrieve_vec(&mut self, size: usize) -> Vec<SignedTransaction>{
-        let mut ret_vec: Vec<SignedTransaction> = Vec::new();
-        for (key, value) in self.data.clone().into_iter(){
-            ret_vec.push(value);
-            self.data.remove(&key);
-            if ret_vec.len() == size{
+    /// Remove a single entry. Entries spending its outputs stay, which is what is wanted when it
+    /// was confirmed.
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let descendants = self.descendants(hash);
+        let entry = self.data.remove(hash)?;
+        for outpoint in spent_outpoints(&entry.transaction.transaction) {
+            self.spends.remove(&outpoint);
+        }
+        for parent in entry.parents.iter() {
+            self.data.get_mut(parent).unwrap().children.remove(hash);
+        }
+        for child in entry.children.iter() {
+            self.data.get_mut(child).unwrap().parents.remove(hash);
+        }
+        self.by_fee_rate.remove(&(entry.fee_rate(), *hash));
+        self.by_time.remove(&(entry.time, *hash));
+        self.total_size -= 1;
+        self.total_bytes -= entry.size;
+        self.update_ancestor_totals(&descendants);
+        Some(entry.transaction)
+    }
+
+    /// Recompute the ancestor totals of the given entries after their ancestors changed
+    fn update_ancestor_totals(&mut self, hashes: &HashSet<H256>) {
+        for hash in hashes.iter() {
+            let ancestors = self.ancestors(hash);
+            let fee = ancestors.iter().map(|h| self.data[h].fee).fold(0, u64::saturating_add);
+            let size: usize = ancestors.iter().map(|h| self.data[h].size).sum();
+            if let Some(entry) = self.data.get_mut(hash) {
+                entry.ancestor_fee = entry.fee.saturating_add(fee);
+                entry.ancestor_size = entry.size + size;
+                entry.ancestor_count = ancestors.len() + 1;
+            }
+        }
+    }
+
+    /// Remove an entry together with every entry that spends its outputs, directly or not
+    pub fn remove_with_descendants(&mut self, hash: &H256) -> Vec<SignedTransaction> {
+        let descendants = self.descendants(hash);
+        let mut removed: Vec<SignedTransaction> = self.remove(hash).into_iter().collect();
+        for descendant in descendants.iter() {
+            removed.extend(self.remove(descendant));
+        }
+        removed
+    }
+
+    /// Unconfirmed transactions that `hash` spends from, directly or not
+    pub fn ancestors(&self, hash: &H256) -> HashSet<H256> {
+        self.closure(hash, |entry| &entry.parents)
+    }
+
+    /// Mempool transactions that spend from `hash`, directly or not
+    pub fn descendants(&self, hash: &H256) -> HashSet<H256> {
+        self.closure(hash, |entry| &entry.children)
+    }
+
+    fn closure<F: Fn(&MempoolEntry) -> &HashSet<H256>>(&self, hash: &H256, next: F) -> HashSet<H256> {
+        let mut found = HashSet::new();
+        let mut stack = vec![*hash];
+        while let Some(h) = stack.pop() {
+            if let Some(entry) = self.data.get(&h) {
+                for n in next(entry).iter() {
+                    if found.insert(*n) {
+                        stack.push(*n);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Fee rate of an entry together with all of its unconfirmed ancestors, which a miner has to
+    /// include along with it
+    pub fn ancestor_fee_rate(&self, hash: &H256) -> u64 {
+        let entry = &self.data[hash];
+        fee_rate(entry.ancestor_fee, entry.ancestor_size)
+    }
+
+    /// Evict the lowest fee rate entries that nothing depends on until the mempool is within its
+    /// size limits, so that a parent stays as long as a child is paying for it
+    fn trim(&mut self) {
+        while self.data.len() > self.policy.max_count || self.total_bytes > self.policy.max_bytes {
+            let (_, hash) = *self.by_fee_rate.iter().find(|(_, h)| self.data[h].children.is_empty()).unwrap();
+            debug!("Mempool full, evicting {}", hash);
+            self.remove(&hash);
+        }
+    }
+
+    /// Drop the entries that have waited longer than the policy's expiry as of `now`, along
+    /// with their descendants, and return how many entries were dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let mut expired = 0;
+        while let Some(&(time, hash)) = self.by_time.iter().next() {
+            if now.saturating_duration_since(time) < self.policy.expiry {
                 break;
             }
+            expired += self.remove_with_descendants(&hash).len();
+        }
+        expired
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        let mut buckets: BTreeMap<u64, FeeRateBucket> = BTreeMap::new();
+        for entry in self.data.values() {
+            let fee_rate = entry.fee_rate();
+            let min_fee_rate = if fee_rate == 0 { 0 } else { 1 << (63 - fee_rate.leading_zeros()) };
+            let bucket = buckets.entry(min_fee_rate).or_insert(FeeRateBucket{min_fee_rate, count: 0, bytes: 0});
+            bucket.count += 1;
+            bucket.bytes += entry.size;
+        }
+        MempoolStats {
+            count: self.data.len(),
+            bytes: self.total_bytes,
+            total_fee: self.data.values().map(|entry| entry.fee).fold(0, u64::saturating_add),
+            fee_rate_histogram: buckets.into_values().collect(),
+        }
+    }
+
+    /// Hashes of the entries that spend an output `transaction` also spends
+    pub fn conflicts(&self, transaction: &SignedTransaction) -> Vec<H256> {
+        let mut hashes: Vec<H256> = spent_outpoints(&transaction.transaction).iter()
+            .filter_map(|outpoint| self.spends.get(outpoint).copied())
+            .collect();
+        hashes.sort();
+        hashes.dedup();
+        hashes
+    }
+
+    /// Remove the transactions of a newly connected block, along with every entry that spends
+    /// the same outputs as one of them, and their descendants, which can therefore never confirm
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        for signed_tx in block.content.content.iter() {
+            self.remove(&signed_tx.hash());
+            for hash in self.conflicts(signed_tx) {
+                self.remove_with_descendants(&hash);
+            }
+        }
+    }
+
+    /// Follow a `StateChain::sync` that disconnected and connected the given blocks and left
+    /// `state` at the blockchain tip. Confirmed and conflicting entries are evicted. After a
+    /// reorganization, transactions of the disconnected blocks that are still valid return to
+    /// the mempool, and entries that no longer apply to `state` are dropped.
+    pub fn sync(&mut self, blockchain: &Blockchain, state: &State, disconnected: &[H256], connected: &[H256]) {
+        for blockhash in connected.iter() {
+            self.remove_confirmed(&blockchain.data[blockhash].block_content);
+        }
+        if disconnected.is_empty() {
+            return;
+        }
+        let height = blockchain.tip_height + 1;
+        let params = &blockchain.params;
+        // oldest block first, so the mempool sees transactions in their original order
+        for blockhash in disconnected.iter().rev() {
+            for signed_tx in blockchain.data[blockhash].block_content.content.content.iter() {
+                if signed_tx.transaction.is_coinbase() {
+                    continue;
+                }
+                let fee = validation::check_transaction(signed_tx, &self.view(state), height, params);
+                if let Ok(fee) = fee {
+                    self.insert(signed_tx, fee);
+                }
+            }
+        }
+        let invalid: Vec<H256> = self.data.iter()
+            .filter(|(_, entry)| validation::check_transaction(&entry.transaction, &self.view(state), height, params).is_err())
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in invalid.iter() {
+            self.remove_with_descendants(hash);
+        }
+    }
+
+    /// Pick transactions for a block whose total serialized size fits in `max_bytes`. Each entry
+    /// is taken as a package with its unconfirmed ancestors, in decreasing order of ancestor fee
+    /// rate, so a child paying a high fee pulls in its parent. Ancestors always come before their
+    /// descendants, and packages that do not fit are skipped in favor of smaller ones.
+    pub fn select(&self, max_bytes: usize) -> Vec<SignedTransaction>{
+        let mut candidates: Vec<(u64, H256)> = self.data.keys().map(|h| (self.ancestor_fee_rate(h), *h)).collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        let mut selected: HashSet<H256> = HashSet::new();
+        let mut ret_vec: Vec<SignedTransaction> = Vec::new();
+        let mut bytes = 0;
+        for (_, hash) in candidates{
+            if selected.contains(&hash){
+                continue;
+            }
+            let mut package: Vec<H256> = self.ancestors(&hash).into_iter().filter(|h| !selected.contains(h)).collect();
+            package.push(hash);
+            let package_size: usize = package.iter().map(|h| self.data[h].size).sum();
+            if bytes + package_size > max_bytes{
+                continue;
+            }
+            // an ancestor always has fewer ancestors than its descendants
+            package.sort_by_key(|h| self.data[h].ancestor_count);
+            for h in package{
+                selected.insert(h);
+                ret_vec.push(self.data[&h].transaction.clone());
+            }
+            bytes += package_size;
         }
         ret_vec
     }
 }
 
+/// The unspent outputs of a state together with the outputs of every mempool transaction
+pub struct MempoolView<'a> {
+    mempool: &'a Mempool,
+    base: &'a dyn UtxoView,
+}
+
+impl<'a> UtxoView for MempoolView<'a> {
+    fn utxo(&self, outpoint: &Outpoint) -> Option<Utxo> {
+        match self.mempool.data.get(&outpoint.0) {
+            Some(entry) => entry.transaction.transaction.out_put.get(outpoint.1).map(|o| (o.value, o.address)),
+            None => self.base.utxo(outpoint),
+        }
+    }
+
+    fn coinbase_height(&self, outpoint: &Outpoint) -> Option<u32> {
+        if self.mempool.data.contains_key(&outpoint.0) {
+            return None;
+        }
+        self.base.coinbase_height(outpoint)
+    }
+}
+
+
+/// An unspent output is referenced by the hash of its transaction and its index in `out_put`
+pub type Outpoint = (H256, usize);
+/// The value and owner address of an unspent output
+pub type Utxo = (u64, H160);
+
+/// Read access to a set of unspent outputs
+pub trait UtxoView {
+    fn utxo(&self, outpoint: &Outpoint) -> Option<Utxo>;
+
+    /// Height of the block whose coinbase created this unspent output, if it is a coinbase output
+    fn coinbase_height(&self, outpoint: &Outpoint) -> Option<u32>;
+}
 
 #[derive(Clone)]
 pub struct State {
-    pub data: HashMap<(H256, usize), (u64, H160)>,
+    pub data: HashMap<Outpoint, Utxo>,
+    /// Creation height of every unspent coinbase output, for the maturity rule
+    coinbase_heights: HashMap<Outpoint, u32>,
+}
+
+impl UtxoView for State {
+    fn utxo(&self, outpoint: &Outpoint) -> Option<Utxo> {
+        self.data.get(outpoint).copied()
+    }
+
+    fn coinbase_height(&self, outpoint: &Outpoint) -> Option<u32> {
+        self.coinbase_heights.get(outpoint).copied()
+    }
+}
+
+/// What a single transaction did to the state, so that it can be reverted
+#[derive(Clone, Default)]
+struct TxUndo {
+    spent: Vec<(Outpoint, Utxo)>,
+    spent_coinbase: Vec<(Outpoint, u32)>,
+    created: Vec<Outpoint>,
+}
+
+/// The changes a connected block made to the state, in the order its transactions were applied
+#[derive(Clone, Default)]
+pub struct BlockUndo {
+    txs: Vec<TxUndo>,
+}
+
+impl Transaction {
+    /// A coinbase transaction creates new coins instead of spending existing outputs
+    pub fn is_coinbase(&self) -> bool {
+        self.in_put.iter().any(|input| input.coin_base)
+    }
+
+    /// Height of the block a coinbase transaction belongs to
+    pub fn coinbase_height(&self) -> Option<u32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        Some(self.in_put[0].index as u32)
+    }
 }
 
 impl State{
     pub fn new() -> Self {
-        State{data: HashMap::new()}
+        State{data: HashMap::new(), coinbase_heights: HashMap::new()}
     }
 
-    pub fn update(&mut self, transactions: Vec<SignedTransaction>) -> (Vec<SignedTransaction>, Vec<SignedTransaction>){
+    /// Apply every transaction that is valid against the state so far for a block at `height`,
+    /// and report which transactions were accepted and which were aborted, along with the total
+    /// fee of the accepted ones
+    pub fn update(&mut self, transactions: Vec<SignedTransaction>, height: u32, params: &Params) -> (Vec<SignedTransaction>, Vec<SignedTransaction>, u64){
         let mut accept_vec: Vec<SignedTransaction> = Vec::new();
         let mut abort_vec: Vec<SignedTransaction> = Vec::new();
+        let mut fees: u64 = 0;
         for signed_tx in transactions{
-            // signature checks of the transaction
-            if verify(&signed_tx.transaction, signed_tx.clone().pub_key, signed_tx.clone().signature) == false{
-                abort_vec.push(signed_tx.clone());
-                continue;
+            let fee = match validation::check_transaction(&signed_tx, self, height, params) {
+                Ok(fee) => fee,
+                Err(_) => {
+                    abort_vec.push(signed_tx);
+                    continue;
+                }
+            };
+            fees = fees.saturating_add(fee);
+            self.apply(&signed_tx);
+            accept_vec.push(signed_tx);
+        }
+        (accept_vec, abort_vec, fees)
+    }
+
+    /// Spend the inputs of a transaction and add its outputs, without any checks
+    fn apply(&mut self, signed_tx: &SignedTransaction) -> TxUndo {
+        let mut tx_undo = TxUndo::default();
+        for outpoint in spent_outpoints(&signed_tx.transaction) {
+            if let Some(spent) = self.data.remove(&outpoint) {
+                tx_undo.spent.push((outpoint, spent));
             }
-            // double spend checks of the transaction
-            if self.data.contains_key(&(signed_tx.transaction.in_put[0].tx_hash, signed_tx.transaction.in_put[0].index)) == false{
-                abort_vec.push(signed_tx.clone());
-                continue;
+            if let Some(height) = self.coinbase_heights.remove(&outpoint) {
+                tx_undo.spent_coinbase.push((outpoint, height));
             }
-            // check if the input of tx is the person who make the tx
-            let (_value, addr) = self.data.get(
-                &(signed_tx.transaction.in_put[0].tx_hash, signed_tx.transaction.in_put[0].index)).unwrap().clone();
-            if ( addr == H160::from(H256::from(signed_tx.pub_key.as_slice())) ) == false{
-                abort_vec.push(signed_tx.clone());
-                continue;
+        }
+        let coinbase_height = signed_tx.transaction.coinbase_height();
+        for (i, output) in signed_tx.transaction.out_put.iter().enumerate(){
+            self.data.insert((signed_tx.hash(), i),(output.value, output.address));
+            if let Some(height) = coinbase_height {
+                self.coinbase_heights.insert((signed_tx.hash(), i), height);
+            }
+            tx_undo.created.push((signed_tx.hash(), i));
+        }
+        tx_undo
+    }
+
+    /// Revert the changes recorded in `undo`, last transaction first
+    fn revert(&mut self, undo: &BlockUndo) {
+        for tx_undo in undo.txs.iter().rev() {
+            for outpoint in tx_undo.created.iter() {
+                self.data.remove(outpoint);
+                self.coinbase_heights.remove(outpoint);
+            }
+            for (outpoint, utxo) in tx_undo.spent.iter() {
+                self.data.insert(*outpoint, *utxo);
+            }
+            for (outpoint, height) in tx_undo.spent_coinbase.iter() {
+                self.coinbase_heights.insert(*outpoint, *height);
+            }
+        }
+    }
+}
+
+/// The outputs a transaction spends. Coinbase transactions spend nothing.
+fn spent_outpoints(transaction: &Transaction) -> Vec<Outpoint> {
+    if transaction.is_coinbase() {
+        return Vec::new();
+    }
+    transaction.in_put.iter().map(|input| (input.tx_hash, input.index)).collect()
+}
+
+/// A view of a state with a batch of transactions applied on top, without copying the state
+pub struct UtxoOverlay<'a> {
+    base: &'a dyn UtxoView,
+    spent: HashSet<Outpoint>,
+    created: HashMap<Outpoint, Utxo>,
+    created_coinbase: HashMap<Outpoint, u32>,
+}
+
+impl<'a> UtxoOverlay<'a> {
+    pub fn new(base: &'a dyn UtxoView) -> Self {
+        UtxoOverlay{base, spent: HashSet::new(), created: HashMap::new(), created_coinbase: HashMap::new()}
+    }
+
+    /// Spend the inputs of a transaction and add its outputs, without any checks
+    pub fn apply(&mut self, signed_tx: &SignedTransaction) {
+        for outpoint in spent_outpoints(&signed_tx.transaction) {
+            self.created_coinbase.remove(&outpoint);
+            if self.created.remove(&outpoint).is_none() {
+                self.spent.insert(outpoint);
             }
-            accept_vec.push(signed_tx.clone());
-            self.data.remove(&(signed_tx.transaction.in_put[0].tx_hash, signed_tx.transaction.in_put[0].index));
-            for (i, output) in signed_tx.transaction.out_put.iter().enumerate(){
-                self.data.insert((signed_tx.hash(), i),(output.value, output.address));
+        }
+        let coinbase_height = signed_tx.transaction.coinbase_height();
+        for (i, output) in signed_tx.transaction.out_put.iter().enumerate(){
+            self.created.insert((signed_tx.hash(), i), (output.value, output.address));
+            if let Some(height) = coinbase_height {
+                self.created_coinbase.insert((signed_tx.hash(), i), height);
             }
-        } 
-        (accept_vec, abort_vec)
+        }
+    }
+}
+
+impl<'a> UtxoView for UtxoOverlay<'a> {
+    fn utxo(&self, outpoint: &Outpoint) -> Option<Utxo> {
+        if let Some(utxo) = self.created.get(outpoint) {
+            return Some(*utxo);
+        }
+        if self.spent.contains(outpoint) {
+            return None;
+        }
+        self.base.utxo(outpoint)
+    }
+
+    fn coinbase_height(&self, outpoint: &Outpoint) -> Option<u32> {
+        if self.created.contains_key(outpoint) {
+            return self.created_coinbase.get(outpoint).copied();
+        }
+        if self.spent.contains(outpoint) {
+            return None;
+        }
+        self.base.coinbase_height(outpoint)
     }
 }
 
@@ -146,18 +697,86 @@ pub fn ico3_proc(pubkey_hashes: Vec<H256>) -> State{
 }
 
 
-#[derive(Clone)]
+/// The single canonical UTXO state, kept at the tip of the blockchain. Every connected block
+/// leaves an undo record behind so that the state can be rolled back when the chain reorganizes.
 pub struct StateChain {
-    pub data: HashMap<H256,  HashMap<(H256, usize), (u64, H160)>>,
+    /// UTXO set as of block `tip`
+    pub state: State,
+    /// Hash of the block that `state` reflects
+    pub tip: H256,
+    undo: HashMap<H256, BlockUndo>,
+    /// The block of every transaction connected since genesis, by transaction hash
+    tx_index: HashMap<H256, H256>,
 }
 
 impl StateChain{
-    pub fn new() -> Self {
-        StateChain{data: HashMap::new()}
+    /// Create the state chain at the genesis block with its initial state
+    pub fn new(genesis: H256, genesis_state: State) -> Self {
+        StateChain{state: genesis_state, tip: genesis, undo: HashMap::new(), tx_index: HashMap::new()}
+    }
+
+    /// Apply a block on top of the current tip. The block must have been validated against
+    /// the current state.
+    fn connect(&mut self, blockhash: H256, block: &Block) {
+        debug_assert_eq!(block.header.parent, self.tip);
+        let mut undo = BlockUndo::default();
+        for signed_tx in block.content.content.iter() {
+            undo.txs.push(self.state.apply(signed_tx));
+            self.tx_index.insert(signed_tx.hash(), blockhash);
+        }
+        self.undo.insert(blockhash, undo);
+        self.tip = blockhash;
+    }
+
+    /// Roll the current tip back to its parent
+    fn disconnect(&mut self, block: &Block) {
+        let undo = self.undo.remove(&self.tip).expect("connected block without undo record");
+        self.state.revert(&undo);
+        for signed_tx in block.content.content.iter() {
+            self.tx_index.remove(&signed_tx.hash());
+        }
+        self.tip = block.header.parent;
     }
 
-    pub fn insert(&mut self, blockhash: H256, new_state: State) {
-        self.data.insert(blockhash, new_state.data);
+    /// Get the hash of the block in the current chain that holds a transaction
+    pub fn transaction_block(&self, hash: &H256) -> Option<H256> {
+        self.tx_index.get(hash).copied()
+    }
+
+    /// Get the state as of block `hash`. This is the live state if `hash` is the current tip;
+    /// otherwise it is a copy rolled back to the fork point and forward along `hash`'s branch.
+    pub fn state_at(&self, blockchain: &Blockchain, hash: &H256) -> Cow<'_, State> {
+        if *hash == self.tip {
+            return Cow::Borrowed(&self.state);
+        }
+        let (leave, enter) = blockchain.reorg_path(&self.tip, hash);
+        let mut state = self.state.clone();
+        for blockhash in leave.iter() {
+            state.revert(&self.undo[blockhash]);
+        }
+        for blockhash in enter.iter() {
+            for signed_tx in blockchain.data[blockhash].block_content.content.content.iter() {
+                state.apply(signed_tx);
+            }
+        }
+        Cow::Owned(state)
+    }
+
+    /// Move the state to the blockchain's current tip, disconnecting blocks back to the common
+    /// ancestor and connecting the new branch if the chain reorganized. Returns the hashes of
+    /// the disconnected blocks (tip first) and of the connected blocks (ancestor first).
+    pub fn sync(&mut self, blockchain: &Blockchain) -> (Vec<H256>, Vec<H256>) {
+        let (disconnected, connected) = blockchain.reorg_path(&self.tip, &blockchain.tip());
+        for blockhash in disconnected.iter() {
+            self.disconnect(&blockchain.data[blockhash].block_content);
+        }
+        for blockhash in connected.iter() {
+            self.connect(*blockhash, &blockchain.data[blockhash].block_content);
+        }
+        if !disconnected.is_empty() {
+            info!("Reorganized state: disconnected {} blocks, connected {} blocks", disconnected.len(), connected.len());
+        }
+        (disconnected, connected)
     }
 }
 
@@ -188,4 +807,271 @@ pub mod tests {
         let signature = sign(&t, &key);
         assert!(verify(&t, key.public_key().as_ref().to_vec(), signature));
     }
+
+    /// An unvalidated block. Blocks with content carry twice the work of empty ones.
+    #[cfg(test)]
+    fn block_on(parent: H256, nonce: u32, content: Vec<SignedTransaction>) -> Block {
+        use crate::block::{Content, Header};
+        let mut difficulty = [255; 32];
+        if !content.is_empty() {
+            difficulty[0] = 127;
+        }
+        let header = Header{parent, nonce, difficulty: difficulty.into(), timestamp: 0, merkle_root: H256::default()};
+        Block{header, content: Content{content}}
+    }
+
+    /// Spend one of the ICO outputs owned by `key`, paying `value` to `recipient`
+    #[cfg(test)]
+    fn spend_ico(key: &Ed25519KeyPair, index: usize, recipient: u8, value: u64) -> SignedTransaction {
+        let t = Transaction{in_put: vec![Input{tx_hash: H256::from([0; 32]), index, coin_base: false}],
+                            out_put: vec![Output{address: H160::from([recipient; 32]), value}]};
+        SignedTransaction{signature: sign(&t, key), pub_key: key.public_key().as_ref().to_vec(), transaction: t}
+    }
+
+    #[test]
+    fn mempool_conflicts_and_reorg() {
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.genesis();
+        let mut statechain = StateChain::new(genesis, ico3_proc(vec![H256::from(key.public_key().as_ref()); 3]));
+        let mut mempool = Mempool::new();
+
+        let confirmed = spend_ico(&key, 0, 1, 9000);
+        let conflicting = spend_ico(&key, 0, 2, 9000);
+        let unrelated = spend_ico(&key, 1, 2, 9000);
+        assert!(mempool.insert(&conflicting, 1000));
+        assert!(!mempool.insert(&confirmed, 1000));
+        assert_eq!(mempool.conflicts(&confirmed), vec![conflicting.hash()]);
+        assert!(mempool.insert(&unrelated, 1000));
+
+        // a block confirming a conflicting spend evicts the mempool's one
+        let a1 = block_on(genesis, 0, vec![confirmed.clone()]);
+        blockchain.insert(&a1);
+        let (disconnected, connected) = statechain.sync(&blockchain);
+        mempool.sync(&blockchain, &statechain.state, &disconnected, &connected);
+        assert!(!mempool.data.contains_key(&conflicting.hash()));
+        assert!(mempool.data.contains_key(&unrelated.hash()));
+
+        // a reorganization puts the transactions of the abandoned branch back
+        let b1 = block_on(genesis, 1, vec![]);
+        let b2 = block_on(b1.hash(), 2, vec![]);
+        let b3 = block_on(b2.hash(), 3, vec![unrelated.clone()]);
+        for block in [&b1, &b2, &b3].iter() {
+            blockchain.insert(block);
+        }
+        let (disconnected, connected) = statechain.sync(&blockchain);
+        assert_eq!(disconnected, vec![a1.hash()]);
+        mempool.sync(&blockchain, &statechain.state, &disconnected, &connected);
+        assert_eq!(mempool.data.len(), 1);
+        assert_eq!(mempool.data[&confirmed.hash()].fee, 1000);
+        assert_eq!(mempool.spends[&(H256::from([0; 32]), 0)], confirmed.hash());
+    }
+
+    /// An unsigned transaction spending `inputs` into `outputs` outputs, which is all the
+    /// mempool looks at
+    #[cfg(test)]
+    fn tx_spending(inputs: &[Outpoint], outputs: usize) -> SignedTransaction {
+        let t = Transaction{in_put: inputs.iter().map(|o| Input{tx_hash: o.0, index: o.1, coin_base: false}).collect(),
+                            out_put: vec![Output{address: H160::from([0; 32]), value: 1}; outputs]};
+        SignedTransaction{transaction: t, signature: vec![], pub_key: vec![]}
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let mut mempool = Mempool::new();
+        let parent = tx_spending(&[(H256::from([0; 32]), 0)], 2);
+        let child = tx_spending(&[(parent.hash(), 0)], 1);
+        let grandchild = tx_spending(&[(child.hash(), 0)], 1);
+        let other = tx_spending(&[(H256::from([0; 32]), 1)], 1);
+        assert!(mempool.insert(&parent, 0));
+        assert!(mempool.insert(&child, 1000));
+        assert!(mempool.insert(&other, 100));
+        assert_eq!(mempool.data[&parent.hash()].children, vec![child.hash()].into_iter().collect());
+        assert_eq!(mempool.ancestors(&child.hash()), vec![parent.hash()].into_iter().collect());
+
+        // the child's fee makes the pair more attractive than the other transaction on its own
+        let size = |t: &SignedTransaction| bincode::serialized_size(t).unwrap() as usize;
+        assert!(mempool.ancestor_fee_rate(&child.hash()) > mempool.ancestor_fee_rate(&other.hash()));
+        let hashes = |v: Vec<SignedTransaction>| v.iter().map(|t| t.hash()).collect::<Vec<H256>>();
+        assert_eq!(hashes(mempool.select(1_000_000)), vec![parent.hash(), child.hash(), other.hash()]);
+        assert_eq!(hashes(mempool.select(size(&parent) + size(&child))), vec![parent.hash(), child.hash()]);
+        assert_eq!(hashes(mempool.select(size(&child))), vec![other.hash()]);
+
+        // a full mempool evicts the childless cheap transaction rather than the parent
+        mempool.policy.max_count = 2;
+        assert!(!mempool.insert(&grandchild, 0));
+        assert!(mempool.data.contains_key(&parent.hash()) && mempool.data.contains_key(&child.hash()));
+
+        // confirming the parent leaves the child, without an unconfirmed parent
+        mempool.remove(&parent.hash());
+        assert!(mempool.data[&child.hash()].parents.is_empty());
+        assert_eq!(mempool.ancestor_fee_rate(&child.hash()), mempool.data[&child.hash()].fee_rate());
+
+        // dropping a transaction drops what spends it
+        mempool.policy.max_count = 10;
+        assert!(mempool.insert(&grandchild, 0));
+        assert_eq!(mempool.data[&grandchild.hash()].ancestor_count, 2);
+        assert_eq!(mempool.ancestor_fee_rate(&grandchild.hash()), fee_rate(1000, size(&child) + size(&grandchild)));
+        assert_eq!(mempool.remove_with_descendants(&child.hash()).len(), 2);
+        assert!(mempool.data.is_empty());
+
+        // a parent coming back after its child, as after a reorg, counts toward the child's package
+        assert!(mempool.insert(&child, 1000));
+        assert!(mempool.insert(&parent, 0));
+        assert_eq!(mempool.data[&child.hash()].ancestor_count, 2);
+        assert_eq!(mempool.ancestor_fee_rate(&child.hash()), fee_rate(1000, size(&parent) + size(&child)));
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let outpoint = (H256::from([0; 32]), 0);
+        let original = tx_spending(&[outpoint], 1);
+        let child = tx_spending(&[(original.hash(), 0)], 1);
+        let replacement = tx_spending(&[outpoint], 2);
+        let size = bincode::serialized_size(&replacement).unwrap() as usize;
+
+        let mut mempool = Mempool::new();
+        assert!(mempool.insert(&original, 100));
+        assert!(!mempool.insert(&replacement, 10_000));
+
+        let mut mempool = Mempool::with_policy(MempoolPolicy{replace_by_fee: true, ..Default::default()});
+        assert!(mempool.insert(&original, 100));
+        assert!(mempool.insert(&child, 100));
+        // the replacement must pay for both transactions it evicts, plus its own relay fee
+        let required = 200 + mempool.min_fee(size);
+        assert!(!mempool.insert(&replacement, required - 1));
+        assert!(mempool.insert(&replacement, required));
+        assert_eq!(mempool.data.len(), 1);
+        assert_eq!(mempool.conflicts(&original), vec![replacement.hash()]);
+
+        // nor can a transaction replace the one it spends from
+        let spends_replaced = tx_spending(&[outpoint, (replacement.hash(), 0)], 1);
+        assert!(!mempool.insert(&spends_replaced, 1_000_000));
+    }
+
+    #[test]
+    fn mempool_orders_by_fee_rate() {
+        let txs: Vec<SignedTransaction> = (0..4).map(|i| {
+            let mut t = generate_random_signedtransaction();
+            t.transaction.in_put[0].index = i;
+            t
+        }).collect();
+        let size = bincode::serialized_size(&txs[0]).unwrap() as usize;
+        let mut mempool = Mempool::new();
+        assert!(mempool.insert(&txs[0], 5));
+        assert!(mempool.insert(&txs[1], 50));
+        assert!(mempool.insert(&txs[2], 20));
+        assert!(!mempool.insert(&txs[2], 30));
+        assert_eq!(mempool.data[&txs[1].hash()].fee_rate(), 50 * 1000 / size as u64);
+
+        let hashes = |v: Vec<SignedTransaction>| v.iter().map(|t| t.hash()).collect::<Vec<H256>>();
+        assert_eq!(hashes(mempool.select(10 * size)), vec![txs[1].hash(), txs[2].hash(), txs[0].hash()]);
+        assert_eq!(hashes(mempool.select(2 * size + 1)), vec![txs[1].hash(), txs[2].hash()]);
+
+        // a bigger transaction is skipped when it does not fit, in favor of smaller ones
+        let mut big = txs[3].clone();
+        big.transaction.out_put = vec![Output{address: H160::from([0; 32]), value: 1}; 10];
+        let big_size = bincode::serialized_size(&big).unwrap() as usize;
+        assert!(mempool.insert(&big, 1000));
+        assert_eq!(hashes(mempool.select(big_size)), vec![big.hash()]);
+        assert_eq!(hashes(mempool.select(size)), vec![txs[1].hash()]);
+
+        assert_eq!(mempool.remove(&txs[1].hash()).map(|t| t.hash()), Some(txs[1].hash()));
+        assert!(mempool.remove(&txs[1].hash()).is_none());
+        assert_eq!(mempool.total_size, 3);
+        assert_eq!(hashes(mempool.select(size)), vec![txs[2].hash()]);
+    }
+
+    #[test]
+    fn min_relay_fee() {
+        let mempool = Mempool::with_policy(MempoolPolicy{min_relay_fee: 10, ..Default::default()});
+        assert_eq!(mempool.min_fee(1000), 10);
+        assert_eq!(mempool.min_fee(1001), 11);
+        assert_eq!(mempool.min_fee(200), 2);
+        assert_eq!(Mempool::with_policy(MempoolPolicy{min_relay_fee: 0, ..Default::default()}).min_fee(200), 0);
+    }
+
+    #[test]
+    fn mempool_limits_and_expiry() {
+        let txs: Vec<SignedTransaction> = (0..5).map(|i| {
+            let mut t = generate_random_signedtransaction();
+            t.transaction.in_put[0].index = i;
+            t
+        }).collect();
+        let size = bincode::serialized_size(&txs[0]).unwrap() as usize;
+        let policy = MempoolPolicy{max_count: 3, max_bytes: 10 * size, ..Default::default()};
+        let mut mempool = Mempool::with_policy(policy);
+        for (tx, fee) in txs.iter().zip([30, 10, 20].iter()) {
+            assert!(mempool.insert(tx, *fee));
+        }
+        // the cheapest transaction makes room, and a newcomer paying even less is turned away
+        assert!(mempool.insert(&txs[3], 15));
+        assert!(!mempool.data.contains_key(&txs[1].hash()));
+        assert!(!mempool.insert(&txs[4], 5));
+        assert_eq!((mempool.data.len(), mempool.total_bytes), (3, 3 * size));
+
+        mempool.policy.max_bytes = 2 * size;
+        assert!(mempool.insert(&txs[1], 100));
+        assert_eq!(mempool.data.len(), 2);
+        assert!(mempool.data.contains_key(&txs[0].hash()) && mempool.data.contains_key(&txs[1].hash()));
+
+        let stats = mempool.stats();
+        assert_eq!((stats.count, stats.bytes, stats.total_fee), (2, 2 * size, 130));
+        assert_eq!(stats.fee_rate_histogram.iter().map(|b| b.count).sum::<usize>(), 2);
+        for bucket in stats.fee_rate_histogram.iter() {
+            assert!(bucket.min_fee_rate.is_power_of_two());
+        }
+
+        let expiry = mempool.policy.expiry;
+        assert_eq!(mempool.expire(Instant::now()), 0);
+        assert_eq!(mempool.expire(Instant::now() + expiry), 2);
+        assert_eq!((mempool.data.len(), mempool.total_size, mempool.total_bytes), (0, 0, 0));
+        assert!(mempool.stats().fee_rate_histogram.is_empty());
+    }
+
+    #[test]
+    fn reorg_rolls_back_state() {
+        let key = key_pair::random();
+        let pubkey_hash = H256::from(key.public_key().as_ref());
+        let genesis_state = ico3_proc(vec![pubkey_hash; 3]);
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.genesis();
+        let mut statechain = StateChain::new(genesis, genesis_state.clone());
+
+        // the main branch spends the first ICO output
+        let t = Transaction{in_put: vec![Input{tx_hash: H256::from([0; 32]), index: 0, coin_base: false}],
+                            out_put: vec![Output{address: H160::from([1; 32]), value: 10000}]};
+        let signed_t = SignedTransaction{signature: sign(&t, &key), pub_key: key.public_key().as_ref().to_vec(), transaction: t};
+        let a1 = block_on(genesis, 0, vec![signed_t.clone()]);
+        blockchain.insert(&a1);
+        statechain.sync(&blockchain);
+        assert!(!statechain.state.data.contains_key(&(H256::from([0; 32]), 0)));
+        assert_eq!(statechain.state.data[&(signed_t.hash(), 0)], (10000, H160::from([1; 32])));
+        assert_eq!(statechain.transaction_block(&signed_t.hash()), Some(a1.hash()));
+
+        // a heavier empty branch from genesis rolls the spend back
+        let b1 = block_on(genesis, 1, vec![]);
+        let b2 = block_on(b1.hash(), 2, vec![]);
+        let b3 = block_on(b2.hash(), 3, vec![]);
+        blockchain.insert(&b1);
+        assert_eq!(statechain.sync(&blockchain), (vec![], vec![]));
+        blockchain.insert(&b2);
+        blockchain.insert(&b3);
+        assert_eq!(statechain.sync(&blockchain), (vec![a1.hash()], vec![b1.hash(), b2.hash(), b3.hash()]));
+        assert_eq!(statechain.state.data, genesis_state.data);
+        assert_eq!(statechain.transaction_block(&signed_t.hash()), None);
+
+        // and switching back re-applies it
+        let a2 = block_on(a1.hash(), 4, vec![]);
+        let a3 = block_on(a2.hash(), 5, vec![]);
+        let a4 = block_on(a3.hash(), 6, vec![]);
+        blockchain.insert(&a2);
+        blockchain.insert(&a3);
+        blockchain.insert(&a4);
+        statechain.sync(&blockchain);
+        assert_eq!(statechain.tip, a4.hash());
+        assert!(statechain.state.data.contains_key(&(signed_t.hash(), 0)));
+        assert_eq!(statechain.state.data.len(), 3);
+        assert_eq!(statechain.transaction_block(&signed_t.hash()), Some(a1.hash()));
+    }
 }
@@ -0,0 +1,400 @@
+use crate::block::{Block, Header};
+use crate::blockchain::Blockchain;
+use crate::consensus::{self, Params};
+use crate::crypto::hash::{H256, H160, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::{self, Outpoint, SignedTransaction, UtxoOverlay, UtxoView};
+use std::collections::HashSet;
+
+/// Why a transaction is invalid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    NoInputs,
+    NoOutputs,
+    BadSignature,
+    /// A coinbase input outside of a block's coinbase transaction
+    UnexpectedCoinbase,
+    /// The spent output does not exist, or was already spent
+    MissingInput(Outpoint),
+    /// The spent output does not belong to the signer
+    WrongOwner(Outpoint),
+    /// The spent output was created by a coinbase that is not buried deep enough yet
+    ImmatureCoinbase(Outpoint),
+    /// The same output is spent by more than one input
+    DuplicateInput(Outpoint),
+    /// The input or output values do not fit in a `u64`
+    ValueOverflow,
+    /// The outputs are worth more than the inputs
+    InsufficientInputValue { inputs: u64, outputs: u64 },
+}
+
+/// Why a block is invalid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockValidationError {
+    UnknownParent(H256),
+    /// The block hash is above its difficulty target
+    BadProofOfWork,
+    BadDifficulty { expected: H256, found: H256 },
+    BadMerkleRoot,
+    /// The timestamp is not after the median timestamp of the previous blocks
+    TimestampTooEarly { median_time_past: u128 },
+    /// The timestamp is too far ahead of the local clock
+    TimestampTooLate { now: u128 },
+    TooLarge(usize),
+    NoTransactions,
+    /// The first transaction is not a coinbase
+    MissingCoinbase,
+    /// A coinbase transaction at the given index other than 0
+    CoinbaseNotFirst(usize),
+    /// The coinbase must have exactly one input, which is a coinbase input
+    BadCoinbase,
+    /// The coinbase input's index must be the block height
+    BadCoinbaseHeight { expected: u32, found: usize },
+    /// The coinbase pays more than the block subsidy plus the fees of the block
+    CoinbaseValueTooHigh { value: u64, allowed: u64 },
+    InvalidTransaction(usize, TransactionError),
+}
+
+/// Check a transaction that is not a coinbase against a set of unspent outputs, for inclusion in a
+/// block at `height`. Every input must be an existing, mature output owned by the signer, and the
+/// outputs may not be worth more than the inputs. Returns the fee, which is the difference.
+pub fn check_transaction(signed_tx: &SignedTransaction, view: &dyn UtxoView, height: u32, params: &Params) -> Result<u64, TransactionError> {
+    let tx = &signed_tx.transaction;
+    if tx.in_put.is_empty() {
+        return Err(TransactionError::NoInputs);
+    }
+    if tx.out_put.is_empty() {
+        return Err(TransactionError::NoOutputs);
+    }
+    if tx.is_coinbase() {
+        return Err(TransactionError::UnexpectedCoinbase);
+    }
+    // signature checks of the transaction, which also rejects malformed public keys
+    if !transaction::verify(tx, signed_tx.pub_key.clone(), signed_tx.signature.clone()) {
+        return Err(TransactionError::BadSignature);
+    }
+    let signer = H160::from(H256::from(&signed_tx.pub_key[..]));
+    let mut seen = HashSet::new();
+    let mut inputs: u64 = 0;
+    for input in tx.in_put.iter() {
+        let outpoint = (input.tx_hash, input.index);
+        if !seen.insert(outpoint) {
+            return Err(TransactionError::DuplicateInput(outpoint));
+        }
+        // double spend checks of the transaction
+        let (value, owner) = view.utxo(&outpoint).ok_or(TransactionError::MissingInput(outpoint))?;
+        // check if the input of tx is the person who make the tx
+        if owner != signer {
+            return Err(TransactionError::WrongOwner(outpoint));
+        }
+        if let Some(created) = view.coinbase_height(&outpoint) {
+            if height < created.saturating_add(params.coinbase_maturity) {
+                return Err(TransactionError::ImmatureCoinbase(outpoint));
+            }
+        }
+        inputs = inputs.checked_add(value).ok_or(TransactionError::ValueOverflow)?;
+    }
+    let outputs = tx.out_put.iter().try_fold(0u64, |sum, o| sum.checked_add(o.value))
+        .ok_or(TransactionError::ValueOverflow)?;
+    if outputs > inputs {
+        return Err(TransactionError::InsufficientInputValue{inputs, outputs});
+    }
+    Ok(inputs - outputs)
+}
+
+/// Sum of the outputs of a transaction, saturating on overflow
+fn output_value(signed_tx: &SignedTransaction) -> u64 {
+    signed_tx.transaction.out_put.iter().map(|o| o.value).fold(0, u64::saturating_add)
+}
+
+/// Check a header against its parent header in the blockchain: proof of work, difficulty and
+/// timestamp. `now` is the local time in milliseconds since the UNIX epoch.
+pub fn check_header(header: &Header, blockchain: &Blockchain, now: u128) -> Result<(), BlockValidationError> {
+    if !blockchain.has_header(&header.parent) {
+        return Err(BlockValidationError::UnknownParent(header.parent));
+    }
+    let expected = blockchain.next_difficulty(&header.parent);
+    if header.difficulty != expected {
+        return Err(BlockValidationError::BadDifficulty{expected, found: header.difficulty});
+    }
+    if header.hash() > header.difficulty {
+        return Err(BlockValidationError::BadProofOfWork);
+    }
+    let median_time_past = blockchain.median_time_past(&header.parent);
+    if header.timestamp <= median_time_past {
+        return Err(BlockValidationError::TimestampTooEarly{median_time_past});
+    }
+    if header.timestamp > now + blockchain.params.max_future_block_time as u128 {
+        return Err(BlockValidationError::TimestampTooLate{now});
+    }
+    Ok(())
+}
+
+/// Context-free checks of a block's content: size, merkle root and coinbase placement
+pub fn check_body(block: &Block, params: &Params) -> Result<(), BlockValidationError> {
+    let transactions = &block.content.content;
+    if transactions.is_empty() {
+        return Err(BlockValidationError::NoTransactions);
+    }
+    let size = bincode::serialized_size(block).unwrap() as usize;
+    if size > params.max_block_size {
+        return Err(BlockValidationError::TooLarge(size));
+    }
+    if MerkleTree::new(transactions).root() != block.header.merkle_root {
+        return Err(BlockValidationError::BadMerkleRoot);
+    }
+    if let Some(i) = transactions.iter().skip(1).position(|tx| tx.transaction.is_coinbase()) {
+        return Err(BlockValidationError::CoinbaseNotFirst(i + 1));
+    }
+    let first = &transactions[0].transaction;
+    if !first.is_coinbase() {
+        return Err(BlockValidationError::MissingCoinbase);
+    }
+    if first.in_put.len() != 1 || first.out_put.is_empty() {
+        return Err(BlockValidationError::BadCoinbase);
+    }
+    Ok(())
+}
+
+/// Check every transaction of a block at `height` in order against `state`, the state as of the
+/// parent, and check that the coinbase claims no more than the subsidy plus the fees.
+/// The block must have passed `check_body`.
+pub fn check_transactions(block: &Block, state: &dyn UtxoView, height: u32, params: &Params) -> Result<(), BlockValidationError> {
+    let transactions = &block.content.content;
+    let coinbase = &transactions[0];
+    let coinbase_index = coinbase.transaction.in_put[0].index;
+    if coinbase_index != height as usize {
+        return Err(BlockValidationError::BadCoinbaseHeight{expected: height, found: coinbase_index});
+    }
+    let mut view = UtxoOverlay::new(state);
+    view.apply(coinbase);
+    let mut fees: u64 = 0;
+    for (i, signed_tx) in transactions.iter().enumerate().skip(1) {
+        let fee = check_transaction(signed_tx, &view, height, params)
+            .map_err(|e| BlockValidationError::InvalidTransaction(i, e))?;
+        fees = fees.saturating_add(fee);
+        view.apply(signed_tx);
+    }
+    let value = output_value(coinbase);
+    let allowed = consensus::block_subsidy(params, height).saturating_add(fees);
+    if value > allowed {
+        return Err(BlockValidationError::CoinbaseValueTooHigh{value, allowed});
+    }
+    Ok(())
+}
+
+/// Run every check on a block that extends its parent in the blockchain, with `state` being
+/// the state as of that parent and `now` the local time in milliseconds
+pub fn validate_block(block: &Block, blockchain: &Blockchain, state: &dyn UtxoView, now: u128) -> Result<(), BlockValidationError> {
+    if !blockchain.data.contains_key(&block.header.parent) {
+        return Err(BlockValidationError::UnknownParent(block.header.parent));
+    }
+    check_header(&block.header, blockchain, now)?;
+    check_body(block, &blockchain.params)?;
+    let height = blockchain.height(&block.header.parent).unwrap() + 1;
+    check_transactions(block, state, height, &blockchain.params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Content;
+    use crate::crypto::key_pair;
+    use crate::transaction::{sign, ico3_proc, Input, Output, State, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const NOW: u128 = 1_600_000_000_000;
+
+    fn address(key: &Ed25519KeyPair) -> H160 {
+        H160::from(H256::from(key.public_key().as_ref()))
+    }
+
+    fn spend(key: &Ed25519KeyPair, outpoint: Outpoint, value: u64) -> SignedTransaction {
+        spend_many(key, &[outpoint], &[value])
+    }
+
+    fn spend_many(key: &Ed25519KeyPair, outpoints: &[Outpoint], values: &[u64]) -> SignedTransaction {
+        let t = Transaction{
+            in_put: outpoints.iter().map(|o| Input{tx_hash: o.0, index: o.1, coin_base: false}).collect(),
+            out_put: values.iter().map(|&value| Output{address: address(key), value}).collect()};
+        SignedTransaction{signature: sign(&t, key), pub_key: key.public_key().as_ref().to_vec(), transaction: t}
+    }
+
+    /// A coinbase at height 1 that claims the subsidy, followed by `txs`
+    fn with_coinbase(txs: Vec<SignedTransaction>) -> Vec<SignedTransaction> {
+        let subsidy = Params::default().initial_subsidy;
+        let mut content = vec![SignedTransaction::coinbase(1, H160::from([7; 32]), subsidy)];
+        content.extend(txs);
+        content
+    }
+
+    /// A block on `parent` with a correct merkle root and a nonce that solves the difficulty
+    fn mine(blockchain: &Blockchain, parent: H256, content: Vec<SignedTransaction>) -> Block {
+        let difficulty = blockchain.next_difficulty(&parent);
+        let merkle_root = MerkleTree::new(&content).root();
+        let header = solve(Header{parent, nonce: 0, difficulty, timestamp: NOW, merkle_root});
+        Block{header, content: Content{content}}
+    }
+
+    fn solve(mut header: Header) -> Header {
+        while header.hash() > header.difficulty {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn setup() -> (Blockchain, State, Ed25519KeyPair) {
+        let key = key_pair::random();
+        let state = ico3_proc(vec![H256::from(key.public_key().as_ref()); 3]);
+        (Blockchain::new(), state, key)
+    }
+
+    #[test]
+    fn valid_block() {
+        let (blockchain, state, key) = setup();
+        let txs = vec![spend(&key, (H256::default(), 0), 10), spend(&key, (H256::default(), 1), 10)];
+        let block = mine(&blockchain, blockchain.tip(), with_coinbase(txs));
+        assert_eq!(validate_block(&block, &blockchain, &state, NOW), Ok(()));
+    }
+
+    #[test]
+    fn bad_header() {
+        let (blockchain, state, key) = setup();
+        let block = mine(&blockchain, blockchain.tip(), with_coinbase(vec![spend(&key, (H256::default(), 0), 10)]));
+
+        let mut unsolved = block.clone();
+        while unsolved.hash() <= unsolved.header.difficulty {
+            unsolved.header.nonce += 1;
+        }
+        assert_eq!(validate_block(&unsolved, &blockchain, &state, NOW), Err(BlockValidationError::BadProofOfWork));
+
+        let mut easier = block.clone();
+        easier.header.difficulty = H256::max_value();
+        assert!(matches!(validate_block(&easier, &blockchain, &state, NOW), Err(BlockValidationError::BadDifficulty{..})));
+
+        let mut early = block.header.clone();
+        early.timestamp = 12345;
+        assert_eq!(check_header(&solve(early), &blockchain, NOW), Err(BlockValidationError::TimestampTooEarly{median_time_past: 12345}));
+        let now = NOW - blockchain.params.max_future_block_time as u128 - 1;
+        assert_eq!(check_header(&block.header, &blockchain, now), Err(BlockValidationError::TimestampTooLate{now}));
+    }
+
+    #[test]
+    fn bad_body() {
+        let (blockchain, state, key) = setup();
+        let mut block = mine(&blockchain, blockchain.tip(), with_coinbase(vec![spend(&key, (H256::default(), 0), 10)]));
+        block.content.content.push(spend(&key, (H256::default(), 1), 10));
+        assert_eq!(validate_block(&block, &blockchain, &state, NOW), Err(BlockValidationError::BadMerkleRoot));
+
+        let empty = Block{header: block.header.clone(), content: Content{content: vec![]}};
+        assert_eq!(check_body(&empty, &blockchain.params), Err(BlockValidationError::NoTransactions));
+
+        let mut params = blockchain.params.clone();
+        params.max_block_size = 100;
+        assert!(matches!(check_body(&block, &params), Err(BlockValidationError::TooLarge(_))));
+
+        let coinbase = SignedTransaction::coinbase(1, address(&key), 10);
+        let missing = mine(&blockchain, blockchain.tip(), vec![spend(&key, (H256::default(), 0), 10)]);
+        assert_eq!(check_body(&missing, &blockchain.params), Err(BlockValidationError::MissingCoinbase));
+        let misplaced = mine(&blockchain, blockchain.tip(), vec![spend(&key, (H256::default(), 0), 10), coinbase.clone()]);
+        assert_eq!(check_body(&misplaced, &blockchain.params), Err(BlockValidationError::CoinbaseNotFirst(1)));
+        let mut two_inputs = coinbase;
+        two_inputs.transaction.in_put.push(two_inputs.transaction.in_put[0].clone());
+        let block = mine(&blockchain, blockchain.tip(), vec![two_inputs]);
+        assert_eq!(check_body(&block, &blockchain.params), Err(BlockValidationError::BadCoinbase));
+    }
+
+    #[test]
+    fn value_conservation() {
+        let (blockchain, state, key) = setup();
+        let params = &blockchain.params;
+        let (a, b, c) = ((H256::default(), 0), (H256::default(), 1), (H256::default(), 2));
+        assert_eq!(check_transaction(&spend_many(&key, &[a, b], &[15000, 4000]), &state, 1, params), Ok(1000));
+        assert_eq!(check_transaction(&spend_many(&key, &[a, b, c], &[10000, 20000]), &state, 1, params), Ok(0));
+        assert_eq!(check_transaction(&spend_many(&key, &[a, b], &[15000, 5001]), &state, 1, params),
+                   Err(TransactionError::InsufficientInputValue{inputs: 20000, outputs: 20001}));
+        assert_eq!(check_transaction(&spend_many(&key, &[a, b, a], &[100]), &state, 1, params),
+                   Err(TransactionError::DuplicateInput(a)));
+        assert_eq!(check_transaction(&spend_many(&key, &[a], &[u64::MAX, 1]), &state, 1, params),
+                   Err(TransactionError::ValueOverflow));
+
+        // every input must belong to the signer, not just the first one
+        let stranger = key_pair::random();
+        let mixed = ico3_proc(vec![H256::from(key.public_key().as_ref()), H256::from(stranger.public_key().as_ref()),
+                                   H256::from(key.public_key().as_ref())]);
+        assert_eq!(check_transaction(&spend_many(&key, &[a, b], &[100]), &mixed, 1, params), Err(TransactionError::WrongOwner(b)));
+
+        // all inputs are spent when the transaction is applied
+        let tx = spend_many(&key, &[a, b], &[100]);
+        let block = mine(&blockchain, blockchain.tip(), with_coinbase(vec![tx, spend(&key, b, 100)]));
+        assert_eq!(validate_block(&block, &blockchain, &state, NOW),
+                   Err(BlockValidationError::InvalidTransaction(2, TransactionError::MissingInput(b))));
+    }
+
+    #[test]
+    fn coinbase_value_and_height() {
+        let (blockchain, state, key) = setup();
+        let subsidy = blockchain.params.initial_subsidy;
+        // the first spend leaves a fee of 10000 - 9000, the second one of 10000 - 9500
+        let txs = vec![spend(&key, (H256::default(), 0), 9000), spend(&key, (H256::default(), 1), 9500)];
+        let mut content = txs.clone();
+        content.insert(0, SignedTransaction::coinbase(1, address(&key), subsidy + 1500));
+        let block = mine(&blockchain, blockchain.tip(), content);
+        assert_eq!(validate_block(&block, &blockchain, &state, NOW), Ok(()));
+
+        let mut content = txs.clone();
+        content.insert(0, SignedTransaction::coinbase(1, address(&key), subsidy + 1501));
+        let greedy = mine(&blockchain, blockchain.tip(), content);
+        assert_eq!(validate_block(&greedy, &blockchain, &state, NOW),
+                   Err(BlockValidationError::CoinbaseValueTooHigh{value: subsidy + 1501, allowed: subsidy + 1500}));
+
+        let mut content = txs;
+        content.insert(0, SignedTransaction::coinbase(2, address(&key), subsidy));
+        let wrong_height = mine(&blockchain, blockchain.tip(), content);
+        assert_eq!(validate_block(&wrong_height, &blockchain, &state, NOW),
+                   Err(BlockValidationError::BadCoinbaseHeight{expected: 1, found: 2}));
+    }
+
+    #[test]
+    fn coinbase_maturity() {
+        let (_blockchain, state, key) = setup();
+        let params = Params::default();
+        let coinbase = SignedTransaction::coinbase(5, address(&key), 50);
+        let mut view = UtxoOverlay::new(&state);
+        view.apply(&coinbase);
+        let outpoint = (coinbase.hash(), 0);
+        let spending = spend(&key, outpoint, 50);
+        let mature_height = 5 + params.coinbase_maturity;
+        assert_eq!(check_transaction(&spending, &view, mature_height - 1, &params), Err(TransactionError::ImmatureCoinbase(outpoint)));
+        assert_eq!(check_transaction(&spending, &view, mature_height, &params), Ok(0));
+        // a coinbase cannot be relayed or included anywhere but first in a block
+        assert_eq!(check_transaction(&coinbase, &view, mature_height, &params), Err(TransactionError::UnexpectedCoinbase));
+    }
+
+    #[test]
+    fn bad_transactions() {
+        let (blockchain, state, key) = setup();
+        let outpoint = (H256::default(), 0);
+        let double_spend = mine(&blockchain, blockchain.tip(), with_coinbase(vec![spend(&key, outpoint, 10), spend(&key, outpoint, 20)]));
+        assert_eq!(validate_block(&double_spend, &blockchain, &state, NOW),
+                   Err(BlockValidationError::InvalidTransaction(2, TransactionError::MissingInput(outpoint))));
+
+        let params = &blockchain.params;
+        let stranger = key_pair::random();
+        assert_eq!(check_transaction(&spend(&stranger, outpoint, 10), &state, 1, params), Err(TransactionError::WrongOwner(outpoint)));
+        let mut forged = spend(&key, outpoint, 10);
+        forged.transaction.out_put[0].value = 10000;
+        assert_eq!(check_transaction(&forged, &state, 1, params), Err(TransactionError::BadSignature));
+        forged.pub_key.truncate(3);
+        assert_eq!(check_transaction(&forged, &state, 1, params), Err(TransactionError::BadSignature));
+
+        // outputs created earlier in the same block can be spent, but not the other way round
+        let first = spend(&key, outpoint, 10);
+        let chained = spend(&key, (first.hash(), 0), 10);
+        let block = mine(&blockchain, blockchain.tip(), with_coinbase(vec![first.clone(), chained.clone()]));
+        assert_eq!(validate_block(&block, &blockchain, &state, NOW), Ok(()));
+        let block = mine(&blockchain, blockchain.tip(), with_coinbase(vec![chained, first.clone()]));
+        assert_eq!(validate_block(&block, &blockchain, &state, NOW),
+                   Err(BlockValidationError::InvalidTransaction(1, TransactionError::MissingInput((first.hash(), 0)))));
+    }
+}